@@ -1,4 +1,4 @@
-use bevy::prelude::Color;
+use bevy::prelude::{Color, KeyCode};
 use bevy_egui::egui::Color32;
 
 /// Contains various costants used throughout the game
@@ -15,15 +15,39 @@ pub const GAME_TITLE_COLOR2: Color32 = Color32::from_rgb(192, 0, 245);
 pub const GAME_TITLE_COLOR3: Color32 = Color32::from_rgb(196, 2, 2);
 pub const GAME_TITLE_COLOR4: Color32 = Color32::from_rgb(10, 117, 0);
 
+// Spell editor syntax highlighting
+pub const SPELL_KEYWORD_COLOR: Color32 = Color32::from_rgb(197, 134, 192);
+pub const SPELL_ADDRESS_COLOR: Color32 = Color32::from_rgb(255, 170, 80);
+pub const SPELL_NUMBER_COLOR: Color32 = Color32::from_rgb(181, 206, 168);
+pub const SPELL_LABEL_COLOR: Color32 = Color32::from_rgb(220, 220, 170);
+pub const SPELL_COMMENT_COLOR: Color32 = Color32::from_rgb(106, 153, 85);
+pub const SPELL_TEXT_COLOR: Color32 = Color32::from_rgb(212, 212, 212);
+pub const SPELL_ERROR_BACKGROUND: Color32 = Color32::from_rgb(90, 20, 20);
+// background of memory cells touched by the step shown in the spell debugger
+pub const MEMORY_TRACE_HIGHLIGHT: Color32 = Color32::from_rgb(70, 70, 140);
+// memory viewer cells are colored by which field of their record they are in
+pub const MEMORY_FIELD_COLORS: [Color32; 4] = [
+  Color32::from_rgb(230, 110, 110),
+  Color32::from_rgb(110, 200, 230),
+  Color32::from_rgb(230, 200, 90),
+  Color32::from_rgb(140, 220, 120),
+];
+
 // Player data
 pub const PLAYER_MAX_HEALTH: f32 = 100.;
 pub const PLAYER_MAX_MAGIKA: f32 = 100.;
 pub const PLAYER_DEFAULT_MOVE_SPEED: u32 = 4;
 pub const PLAYER_ATTACK_DAMAGE: f32 = 50.;
+// How much magika it takes to cast any spell
+pub const SPELL_MAGIKA_COST: f32 = 20.;
+// How many instructions a spell can run for each point of the caster's magika
+pub const SPELL_STEPS_PER_MAGIKA: usize = 100;
 
 // Enemy data
 pub const ENEMY_DEFAULT_MOVE_SPEED: u32 = 3;
 pub const ENEMY_ATTACK_DAMAGE: f32 = 10.;
+// How much magika enemy spellcasters start with, if their level doesn't say
+pub const ENEMY_DEFAULT_MAGIKA: f32 = 60.;
 
 // Z levels
 // map tiles are drawn at z level 1.
@@ -34,6 +58,7 @@ pub const PLAYER_Z_LEVEL: f32 = 4.;
 // In-game UI
 pub const TOP_BAR_MIN_SIZE: f32 = 30.;
 pub const TOP_BAR_DESIRED_SIZE: f32 = 0.1;
+pub const DEBUG_MODE_KEY: KeyCode = KeyCode::F3;
 
 // other
 pub const TILE_SIZE: f32 = 16.; // don't change unless LDtk maps are updated
@@ -1,27 +1,92 @@
+use std::marker::PhantomData;
+
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
 use bevy_egui::{egui, EguiContext};
 
-use crate::spells::wizard_memory::{MemoryLocation, MemoryCell, MemoryBlob, MEMORY_SIZE};
+use crate::constants;
+use crate::spells::wizard_memory::{
+  MemoryBlob, MemoryCell, MemoryCellType, MemoryLocation, MemoryMap, MEMORY_SIZE,
+};
+use crate::spells::wizard_serialize::{FieldLocation, MemoryLayout};
+use crate::spells::wizard_types::FormRegistry;
+use crate::spells::EditMemory;
 use crate::utils::{pad_string_left, pad_string_right};
 
+use super::spell_debugger::SpellDebuggerState;
+use super::DebugMode;
+
 //use super::basic_types::{UIBlocks, UIBlock};
 
 #[derive(Component, Default)]
 pub struct MemoryWindowState {
   current_value: (MemoryLocation, MemoryCell),
   rows_scrolled: usize,
+  /// Text in the "jump to entity" box
+  search: String,
+  search_failed: bool,
+  /// Text in the edit box, only shown in debug mode
+  edit_text: String,
+}
+
+/// Each field of a form gets its own color
+fn field_color(forms: &FormRegistry, location: &FieldLocation) -> egui::Color32 {
+  let index = forms
+    .get(location.record.kind.form_name())
+    .and_then(|form| form.fields.iter().position(|(f, _)| f == location.field))
+    .unwrap_or(0);
+  constants::MEMORY_FIELD_COLORS[index % constants::MEMORY_FIELD_COLORS.len()]
+}
+
+/// Names the entity and field that a memory cell belongs to
+fn describe_cell(location: &FieldLocation, address: usize) -> String {
+  let mut text = format!(
+    "{} {}: {}",
+    location.record.kind.form_name(),
+    location.record.entity.id(),
+    location.field
+  );
+  if location.field_type.element_count() > 1 {
+    let element = (address - location.start) / location.field_type.element_type().size();
+    text.push_str(&format!("[{}]", element));
+  }
+  text
+}
+
+/// Memory, and what is needed to describe each cell of it
+#[derive(SystemParam)]
+pub struct MemoryContents<'w, 's> {
+  memory: Res<'w, MemoryBlob>,
+  map: Res<'w, MemoryMap>,
+  layout: Res<'w, MemoryLayout>,
+  forms: Res<'w, FormRegistry>,
+  #[system_param(ignore)]
+  marker: PhantomData<&'s ()>,
 }
 
 pub fn memory_ui(
   mut gui: ResMut<EguiContext>,
-  mut memory: ResMut<MemoryBlob>,
+  contents: MemoryContents,
+  debug: Res<DebugMode>,
+  mut debugger: ResMut<SpellDebuggerState>,
+  mut edits: EventWriter<EditMemory>,
   //mut blocks: ResMut<UIBlocks>,
   mut view: Query<&mut MemoryWindowState>,
 ) {
   if view.get_single().is_err() {
     return;
   }
+  let MemoryContents {
+    memory,
+    map,
+    layout,
+    forms,
+    ..
+  } = contents;
   let mut view = view.single_mut();
+  if let Some(address) = debugger.jump_to.take() {
+    view.rows_scrolled = address / 10;
+  }
 
   let window = gui.ctx_mut().available_rect();
   let frame_size = window.width() / 2.;
@@ -50,17 +115,39 @@ pub fn memory_ui(
               ui.label(egui::RichText::new(row_label_text).monospace().weak());
 
               for index in start..start + 10 {
-                if let Some(cell) = memory.get_one(MemoryLocation { pointer: index }) {
+                let is_locked = map.region_at(index).is_some_and(|r| !r.permissions.read);
+                if is_locked {
+                  // the form stored here hasn't been unlocked, so its cells are hidden
+                  ui.label(egui::RichText::new("  ?").monospace().weak())
+                    .on_hover_text("This part of memory is locked");
+                } else if let Some(cell) = memory.get_one(MemoryLocation { pointer: index }) {
                   let mut cell_label_text = format!("{}", &cell.value);
                   pad_string_left(&mut cell_label_text, 3);
                   let mut styled_cell_label_text = egui::RichText::new(cell_label_text).monospace();
                   if index == view.current_value.0.pointer {
                     styled_cell_label_text = styled_cell_label_text.underline();
                   }
-                  let k =
+                  let is_traced =
+                    debugger.highlighted.iter().any(|(s, c)| *s <= index && index < s + c);
+                  if is_traced {
+                    styled_cell_label_text =
+                      styled_cell_label_text.background_color(constants::MEMORY_TRACE_HIGHLIGHT);
+                  }
+                  let location = layout
+                    .field_at(index, &forms)
+                    .filter(|_| cell.cell_type == MemoryCellType::Field);
+                  if let Some(location) = &location {
+                    styled_cell_label_text =
+                      styled_cell_label_text.color(field_color(&forms, location));
+                  }
+                  let mut k =
                     ui.add(egui::Label::new(styled_cell_label_text).sense(egui::Sense::click()));
+                  if let Some(location) = &location {
+                    k = k.on_hover_text(describe_cell(location, index));
+                  }
                   if k.clicked() {
                     view.current_value = (MemoryLocation { pointer: index }, cell.to_owned());
+                    view.edit_text = cell.value.to_string();
                   }
                 }
               }
@@ -83,7 +170,55 @@ pub fn memory_ui(
         }
       }
 
-      ui.label(format!("Address {}", view.current_value.0.pointer));
+      let address = view.current_value.0.pointer;
+      if let Some(region) = map.region_at(address) {
+        ui.label(format!("Region: {} ({})", region.name, region.permissions));
+      }
+      let can_read = map.region_at(address).is_none_or(|r| r.permissions.read);
+      match layout.field_at(address, &forms).filter(|_| can_read) {
+        Some(location) => ui.label(format!(
+          "Address {} ({})",
+          address,
+          describe_cell(&location, address)
+        )),
+        None => ui.label(format!("Address {}", address)),
+      };
+
+      ui.horizontal(|ui| {
+        ui.label("Jump to entity");
+        ui.add(egui::TextEdit::singleline(&mut view.search).desired_width(80.))
+          .on_hover_text("An entity id, or Player, Enemy or Tile");
+        if ui.button("Go").clicked() {
+          let search = view.search.trim().to_owned();
+          let record = layout.records.iter().find(|r| {
+            r.entity.id().to_string() == search || r.kind.form_name().eq_ignore_ascii_case(&search)
+          });
+          view.search_failed = record.is_none();
+          if let Some(record) = record {
+            view.rows_scrolled = record.start / 10;
+            if let Some(cell) = memory.get_one(MemoryLocation { pointer: record.start }) {
+              view.current_value = (MemoryLocation { pointer: record.start }, cell.to_owned());
+              view.edit_text = cell.value.to_string();
+            }
+          }
+        }
+        if view.search_failed {
+          ui.colored_label(egui::Color32::RED, "Not found");
+        }
+      });
+
+      if debug.0 {
+        ui.horizontal(|ui| {
+          ui.label("Debug: set cell to");
+          ui.add(egui::TextEdit::singleline(&mut view.edit_text).desired_width(20.));
+          let value = view.edit_text.trim().parse::<u8>().ok().filter(|v| *v <= 9);
+          if ui.add_enabled(value.is_some(), egui::Button::new("Write")).clicked() {
+            if let Some(value) = value {
+              edits.send(EditMemory { address, value });
+            }
+          }
+        });
+      }
     });
 }
 
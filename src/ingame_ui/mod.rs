@@ -1,15 +1,20 @@
 use bevy::prelude::*;
 use bevy_egui::EguiContext;
 
-use crate::GameState;
+use crate::{constants, GameState};
 
 pub mod basic_types;
 pub use basic_types::BlockKeyInput;
 
-use self::{memory_viewer::MemoryWindowState, spell_viewer::SpellViewerState};
+use self::{
+  memory_viewer::MemoryWindowState, spell_console::SpellConsoleState,
+  spell_viewer::SpellViewerState,
+};
 mod memory_viewer;
 mod round_summary;
 mod sides;
+mod spell_console;
+mod spell_debugger;
 mod spell_viewer;
 mod top_bar;
 
@@ -27,15 +32,53 @@ impl PointerStatus {
   }
 }
 
-/// updates `Res<PointerStatus>` to make sure that mouse events are intercepted to egui, and not used in the map
-fn ui_block_check(mut gui_ctx: ResMut<EguiContext>, mut p_status: ResMut<PointerStatus>) {
+/// Stores whether or not egui was using the keyboard (i.e. typing in the spell editor) on the last frame.
+#[derive(Default)]
+pub struct KeyboardStatus(bool);
+
+impl KeyboardStatus {
+  /// If true, disregard key presses meant for the map
+  pub fn disregard_key_event(&self) -> bool {
+    self.0
+  }
+}
+
+/// When on, the memory viewer lets cells be edited directly
+#[derive(Default)]
+pub struct DebugMode(pub bool);
+
+fn toggle_debug_mode(
+  keys: Res<Input<KeyCode>>,
+  k_status: Res<KeyboardStatus>,
+  mut debug: ResMut<DebugMode>,
+) {
+  if !k_status.disregard_key_event() && keys.just_pressed(constants::DEBUG_MODE_KEY) {
+    debug.0 = !debug.0;
+  }
+}
+
+/// updates `Res<PointerStatus>` to make sure that mouse events are intercepted to egui, and not used in the map.
+/// `Res<KeyboardStatus>` is updated in the same way.
+fn ui_block_check(
+  mut gui_ctx: ResMut<EguiContext>,
+  mut p_status: ResMut<PointerStatus>,
+  mut k_status: ResMut<KeyboardStatus>,
+) {
   p_status.0 = gui_ctx.ctx_mut().is_pointer_over_area();
+  k_status.0 = gui_ctx.ctx_mut().wants_keyboard_input();
 }
 
 /// removes a few misc components when we transition from game to menu
 fn game_exit(
   mut commands: Commands,
-  old_entities: Query<Entity, Or<(With<MemoryWindowState>, With<SpellViewerState>)>>,
+  old_entities: Query<
+    Entity,
+    Or<(
+      With<MemoryWindowState>,
+      With<SpellViewerState>,
+      With<SpellConsoleState>,
+    )>,
+  >,
 ) {
   for e in old_entities.iter() {
     commands.entity(e).despawn_recursive();
@@ -49,6 +92,10 @@ impl Plugin for UIPlugin {
     app
       .add_plugin(bevy_egui::EguiPlugin)
       .insert_resource(PointerStatus::default())
+      .insert_resource(KeyboardStatus::default())
+      .insert_resource(DebugMode::default())
+      .insert_resource(spell_debugger::SpellDebuggerState::default())
+      .add_system(toggle_debug_mode)
       // It is important that each egui UI piece is created in the same order every frame, so we make a new single-threaded stage
       // for simplicity. Also need to propogate the state to it.
       .add_stage_after(CoreStage::Update, UI_STAGE, SystemStage::single_threaded())
@@ -58,12 +105,27 @@ impl Plugin for UIPlugin {
         SystemSet::on_update(GameState::Running)
           .with_system(top_bar::top_bar.label("top-bar"))
           .with_system(sides::left_panel.label("left-bar").after("top-bar"))
-          .with_system(memory_viewer::memory_ui.after("top-bar").before("left-bar"))
+          .with_system(
+            spell_debugger::spell_debugger
+              .label("spell-debugger")
+              .after("top-bar")
+              .before("left-bar"),
+          )
+          .with_system(
+            memory_viewer::memory_ui
+              .after("spell-debugger")
+              .before("left-bar"),
+          )
           .with_system(
             spell_viewer::spell_viewer
               .after("top-bar")
               .before("left-bar"),
           )
+          .with_system(
+            spell_console::spell_console
+              .after("top-bar")
+              .before("left-bar"),
+          )
           .with_system(round_summary::round_summary.before("top-bar")),
       )
       .add_system_set(SystemSet::on_exit(GameState::Running).with_system(game_exit))
@@ -2,13 +2,14 @@ use bevy::prelude::*;
 use bevy_egui::{egui, EguiContext};
 
 use crate::level::{RoundSummary, ToMenu};
-use crate::turn::CompletedTurn;
+use crate::turn::{CompletedTurn, TurnLog};
 
 pub fn round_summary(
   mut gui: ResMut<EguiContext>,
   mut quit_event: EventWriter<ToMenu>,
   round_over_q: Query<&RoundSummary>,
   completed_turns: Query<&CompletedTurn>,
+  turn_log: Res<TurnLog>,
 ) {
   let window = gui.ctx_mut().available_rect();
   let left_margin = window.width() / 20.;
@@ -32,6 +33,9 @@ pub fn round_summary(
           "You fought for {} turns.",
           completed_turns.iter().count()
         ));
+        for (turn, entry) in turn_log.entries.iter() {
+          ui.label(format!("turn {}: {}", turn, entry));
+        }
 
         if ui.button("Back To Menu").clicked() {
           quit_event.send(ToMenu);
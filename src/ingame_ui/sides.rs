@@ -8,7 +8,8 @@ use crate::map_entities::enemy::Enemy;
 use crate::map_entities::{player::PlayerStatus, EntityHealth, MapEntityType, PlayerType};
 use crate::spells::AvailableSpell;
 use crate::turn::{
-  EntityAction, EntityPendingAction, PlayerActionChosen, StartTurn, TurnStatus, TurnUIState,
+  EntityAction, EntityPendingAction, PendingMove, PlayerActionChosen, StartTurn, TurnStatus,
+  TurnUIState,
 };
 
 type PlayerStatusQuery<'a> = (
@@ -76,7 +77,7 @@ fn draw_player_status<'a>(
   ui.label("Health:");
   draw_single_bar(
     ui,
-    constants::PLAYER_MAX_HEALTH,
+    health.max,
     health.health,
     egui::Color32::GREEN,
     egui::Color32::RED,
@@ -122,27 +123,24 @@ fn draw_player_status<'a>(
       })
       .inner;
 
-    let mut new_spell = "".to_owned();
     if let Some(cur_spell) = pending_action.get_spell() {
-      let start_index = spells
-        .iter()
-        .enumerate()
-        .find(|(_, spell_name)| **spell_name == cur_spell)
-        .map(|(i, _)| i)
-        .unwrap_or(0);
-      let mut selected_spell = start_index;
-      egui::ComboBox::from_id_source("Spells").show_index(
-        ui,
-        &mut selected_spell,
-        spells.len(),
-        |i| spells[i].to_owned(),
-      );
-      if selected_spell != start_index {
-        new_spell = spells[selected_spell].to_string();
+      let mut selected_spell = cur_spell;
+      egui::ComboBox::from_id_source("Spells")
+        .selected_text(if cur_spell.is_empty() {
+          "Choose a spell"
+        } else {
+          cur_spell
+        })
+        .show_ui(ui, |ui| {
+          for spell in spells.iter() {
+            ui.selectable_value(&mut selected_spell, *spell, *spell);
+          }
+        });
+      if selected_spell != cur_spell {
         return_val = Some((
           p_entity,
           EntityPendingAction {
-            action: EntityAction::Cast(new_spell.clone()),
+            action: EntityAction::Cast(selected_spell.to_string()),
             is_ready: true,
           },
         ));
@@ -155,11 +153,11 @@ fn draw_player_status<'a>(
         match selection {
           UIPlayerAction::Attack => EntityPendingAction::default_attack(),
           UIPlayerAction::Cast => EntityPendingAction {
-            action: EntityAction::Cast(new_spell),
+            action: EntityAction::Cast(String::new()),
             is_ready: false,
           },
           UIPlayerAction::Move => EntityPendingAction {
-            action: EntityAction::Move((TilePos::default(), TilePos::default())),
+            action: EntityAction::Move(PendingMove::default()),
             is_ready: false,
           },
           UIPlayerAction::Wait => EntityPendingAction {
@@ -187,7 +185,7 @@ pub fn left_panel(
   >,
   player_q: Query<PlayerStatusQuery>,
   spells: Query<&AvailableSpell>,
-  enemies: Query<(&TilePos, &EntityHealth), With<Enemy>>,
+  enemies: Query<(&TilePos, &EntityHealth, &Enemy)>,
 ) {
   const PANEL_SIZE_FACTOR: f32 = 4.;
 
@@ -298,11 +296,22 @@ pub fn left_panel(
           ui.label("Health");
           draw_single_bar(
             ui,
-            constants::PLAYER_MAX_HEALTH,
+            e.1.max,
             e.1.health,
             egui::Color32::RED,
             egui::Color32::DARK_RED,
           );
+          if let Some(magika) = e.2.magika {
+            ui.label("Mana");
+            draw_single_bar(
+              ui,
+              e.2.max_magika,
+              magika,
+              egui::Color32::BLUE,
+              egui::Color32::BLUE,
+            );
+            ui.label(format!("Knows {}", e.2.spells.join(", ")));
+          }
         }
       } else {
         ui.label("No tile selected");
@@ -0,0 +1,46 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+
+use crate::spells::SpellConsole;
+
+#[derive(Component, Default)]
+pub struct SpellConsoleState;
+
+/// Shows what spells have printed this level, grouped by turn
+pub fn spell_console(
+  mut gui: ResMut<EguiContext>,
+  console: Res<SpellConsole>,
+  state: Query<&SpellConsoleState>,
+) {
+  if state.is_empty() {
+    return;
+  }
+
+  egui::Window::new("Spell Console").show(gui.ctx_mut(), |ui| {
+    if console.entries.is_empty() {
+      ui.label("Nothing has been printed yet. Use print in a spell to write here.");
+      return;
+    }
+    egui::ScrollArea::vertical()
+      .max_height(250.)
+      .show(ui, |ui| {
+        let mut last_turn = None;
+        for entry in console.entries.iter() {
+          if last_turn != Some(entry.turn) {
+            ui.label(egui::RichText::new(format!("Turn {}", entry.turn)).strong());
+            last_turn = Some(entry.turn);
+          }
+          ui.label(format!("{} cast {}", entry.caster, entry.spell));
+          for line in entry.lines.iter() {
+            ui.monospace(format!("  {}", line));
+          }
+          if let Some(error) = &entry.error {
+            ui.colored_label(egui::Color32::RED, format!("  {}", error));
+          }
+          for conflict in entry.conflicts.iter() {
+            ui.colored_label(egui::Color32::YELLOW, format!("  {}", conflict));
+          }
+        }
+      });
+  });
+}
@@ -0,0 +1,89 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+
+use crate::spells::LatestTrace;
+
+use super::memory_viewer::MemoryWindowState;
+
+/// Which step of the latest spell trace is being looked at
+#[derive(Default)]
+pub struct SpellDebuggerState {
+  step: usize,
+  /// Memory touched by the current step, as `(start, count)`. Highlighted in the memory viewer.
+  pub highlighted: Vec<(usize, usize)>,
+  /// Set when the memory viewer should scroll to show an address
+  pub jump_to: Option<usize>,
+}
+
+/// Steps forward and backward through the trace of the last spell that ran.
+/// Only shown while the memory viewer is open, since that is where touched memory is highlighted.
+pub fn spell_debugger(
+  mut gui: ResMut<EguiContext>,
+  latest: Res<LatestTrace>,
+  mut state: ResMut<SpellDebuggerState>,
+  memory_window: Query<&MemoryWindowState>,
+) {
+  let recorded = match &latest.0 {
+    Some(recorded) if !memory_window.is_empty() => recorded,
+    _ => {
+      state.highlighted.clear();
+      return;
+    }
+  };
+
+  let total = recorded.trace.steps.len();
+  let mut step = if latest.is_changed() {
+    0
+  } else {
+    state.step.min(total.saturating_sub(1))
+  };
+
+  egui::Window::new("Spell Debugger").show(gui.ctx_mut(), |ui| {
+    ui.label(egui::RichText::new(recorded.spell.as_str()).strong());
+    if total == 0 {
+      ui.label("The spell didn't run any instructions");
+    } else {
+      ui.horizontal(|ui| {
+        if ui.add_enabled(step > 0, egui::Button::new("|<")).clicked() {
+          step = 0;
+        }
+        if ui.add_enabled(step > 0, egui::Button::new("<")).clicked() {
+          step -= 1;
+        }
+        ui.label(format!("Step {} of {}", step + 1, total));
+        if ui.add_enabled(step + 1 < total, egui::Button::new(">")).clicked() {
+          step += 1;
+        }
+        if ui.add_enabled(step + 1 < total, egui::Button::new(">|")).clicked() {
+          step = total - 1;
+        }
+      });
+
+      let current = &recorded.trace.steps[step];
+      let source_line = recorded.source.lines().nth(current.line).unwrap_or("");
+      ui.monospace(format!("{:>4} | {}", current.line + 1, source_line.trim()));
+      if current.events.is_empty() {
+        ui.label("No memory was touched");
+      }
+      for event in current.events.iter() {
+        ui.monospace(event.to_string());
+      }
+    }
+
+    if let Some(error) = &recorded.error {
+      ui.colored_label(egui::Color32::RED, error.to_string());
+    }
+  });
+
+  let highlighted: Vec<(usize, usize)> = recorded
+    .trace
+    .steps
+    .get(step)
+    .map(|s| s.events.iter().map(|e| e.cells()).collect())
+    .unwrap_or_default();
+  if step != state.step || latest.is_changed() {
+    state.jump_to = highlighted.first().map(|(start, _)| *start);
+  }
+  state.step = step;
+  state.highlighted = highlighted;
+}
@@ -1,17 +1,188 @@
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
+use bevy_egui::egui::text::{LayoutJob, TextFormat};
 use bevy_egui::{egui, egui::Vec2 as EGVec2, EguiContext};
 
-use crate::spells::AvailableSpell;
+use crate::constants;
+use crate::spells::wizard_bytecode::Bytecode;
+use crate::spells::wizard_costs::ManaCostTable;
+use crate::spells::wizard_memory::{MemoryBlob, MemoryMap};
+use crate::spells::wizard_progression::Progression;
+use crate::spells::wizard_serialize::MemoryLayout;
+use crate::spells::wizard_types::FormRegistry;
+use crate::map_entities::player::PlayerStatus;
+use crate::spells::{
+  dry_run, step_budget, wizard_lang, AvailableSpell, LatestTrace, RecordedTrace, RunOptions,
+  SpellRun,
+};
 
 #[derive(Component, Default)]
 pub struct SpellViewerState {
   pub selected_spell: Option<String>,
+  /// The text in the editor, which might not be saved yet
+  editor_text: String,
+  /// The spell whose source was last loaded into the editor
+  loaded_spell: Option<String>,
+  /// The result of the last simulation, and the source it was run with
+  preview: Option<(String, SpellRun)>,
+}
+
+/// Adds a single line of spell source to the layout job, with syntax highlighting
+fn highlight_line(job: &mut LayoutJob, line: &str, background: egui::Color32) {
+  let (code, comment) = match line.find('#') {
+    Some(i) => line.split_at(i),
+    None => (line, ""),
+  };
+
+  let mut append = |text: &str, color: egui::Color32| {
+    if !text.is_empty() {
+      let mut format = TextFormat::simple(egui::TextStyle::Monospace, color);
+      format.background = background;
+      job.append(text, 0., format);
+    }
+  };
+
+  let mut is_first_word = true;
+  let mut rest = code;
+  while let Some(c) = rest.chars().next() {
+    let (len, color) = if c == '@' || c == '*' {
+      let len = rest[1..]
+        .find(|n: char| !(n.is_ascii_alphanumeric() || n == '+' || n == ':'))
+        .map(|i| i + 1)
+        .unwrap_or(rest.len());
+      (len, constants::SPELL_ADDRESS_COLOR)
+    } else if c.is_ascii_digit() {
+      let len = rest
+        .find(|n: char| !n.is_ascii_digit())
+        .unwrap_or(rest.len());
+      (len, constants::SPELL_NUMBER_COLOR)
+    } else if c.is_ascii_alphabetic() || c == '_' {
+      let len = rest
+        .find(|n: char| !(n.is_ascii_alphanumeric() || n == '_'))
+        .unwrap_or(rest.len());
+      let color = if is_first_word && wizard_lang::INSTRUCTIONS.contains(&&rest[..len]) {
+        constants::SPELL_KEYWORD_COLOR
+      } else {
+        // anything else that looks like a word is a label
+        constants::SPELL_LABEL_COLOR
+      };
+      is_first_word = false;
+      (len, color)
+    } else {
+      (c.len_utf8(), constants::SPELL_TEXT_COLOR)
+    };
+
+    let (token, remaining) = rest.split_at(len);
+    append(token, color);
+    rest = remaining;
+  }
+
+  append(comment, constants::SPELL_COMMENT_COLOR);
+}
+
+/// Highlights spell source, and marks the line with an error (if there is one)
+fn highlight(source: &str, error_line: Option<usize>) -> LayoutJob {
+  let mut job = LayoutJob::default();
+  for (i, line) in source.split('\n').enumerate() {
+    if i > 0 {
+      job.append(
+        "\n",
+        0.,
+        TextFormat::simple(egui::TextStyle::Monospace, constants::SPELL_TEXT_COLOR),
+      );
+    }
+    let background = if error_line == Some(i) {
+      constants::SPELL_ERROR_BACKGROUND
+    } else {
+      egui::Color32::TRANSPARENT
+    };
+    highlight_line(&mut job, line, background);
+  }
+  job
+}
+
+/// Shows the disassembled bytecode of the saved spell
+fn show_bytecode(ui: &mut egui::Ui, program: &Bytecode) {
+  egui::CollapsingHeader::new(format!("Bytecode ({} words)", program.word_count()))
+    .default_open(false)
+    .show(ui, |ui| {
+      egui::ScrollArea::vertical()
+        .max_height(160.)
+        .show(ui, |ui| {
+          ui.monospace(program.disassemble());
+        });
+    });
+}
+
+/// Shows what a simulated spell would do, without changing anything
+fn show_preview(ui: &mut egui::Ui, spell: &AvailableSpell, run: &SpellRun, out_of_date: bool) {
+  ui.separator();
+  ui.label(egui::RichText::new("Simulation").strong());
+  if out_of_date {
+    ui.colored_label(egui::Color32::YELLOW, "The source has changed since this was run");
+  }
+  match &run.result {
+    Ok(output) => {
+      for line in output.printed.iter() {
+        ui.monospace(line);
+      }
+      ui.label(format!("Finished in {} steps", output.steps));
+    }
+    Err(e) => {
+      ui.colored_label(egui::Color32::RED, e.to_string());
+    }
+  }
+
+  if run.changes.is_empty() {
+    ui.label("No changes to the world");
+  }
+  egui::ScrollArea::vertical()
+    .max_height(120.)
+    .show(ui, |ui| {
+      for change in run.changes.iter() {
+        ui.monospace(format!(
+          "@{} {} {} {}[{}]: {} -> {} ({} cells)",
+          change.address,
+          change.kind.form_name(),
+          change.entity.id(),
+          change.field,
+          change.element,
+          change.old,
+          change.new,
+          change.changed_cells,
+        ));
+      }
+    });
+
+  ui.label(format!(
+    "Magika cost: {} ({} to cast, {} for changes)",
+    run.total_cost(),
+    constants::SPELL_MAGIKA_COST,
+    run.change_cost
+  ));
+  if !run.within_budget(spell) {
+    ui.colored_label(egui::Color32::RED, "Over the spell's mana budget");
+  }
+}
+
+/// Everything the Simulate button needs to try a spell out
+#[derive(SystemParam)]
+pub struct SimulationParams<'w, 's> {
+  memory: Res<'w, MemoryBlob>,
+  map: Res<'w, MemoryMap>,
+  layout: Res<'w, MemoryLayout>,
+  forms: Res<'w, FormRegistry>,
+  costs: Res<'w, ManaCostTable>,
+  players: Query<'w, 's, (Entity, &'static PlayerStatus)>,
 }
 
 pub fn spell_viewer(
   mut gui_ctx: ResMut<EguiContext>,
   mut state_q: Query<&mut SpellViewerState>,
-  spells: Query<&AvailableSpell>,
+  mut spells: Query<&mut AvailableSpell>,
+  sim: SimulationParams,
+  progression: Res<Progression>,
+  mut latest_trace: ResMut<LatestTrace>,
 ) {
   let state = state_q.get_single_mut();
   if state.is_err() {
@@ -19,6 +190,19 @@ pub fn spell_viewer(
   }
   let mut state = state.unwrap();
 
+  // Load the newly selected spell into the editor
+  let selected = state.selected_spell.clone();
+  if selected != state.loaded_spell {
+    if let Some(spell) = selected
+      .as_ref()
+      .and_then(|name| spells.iter().find(|s| s.name == *name))
+    {
+      state.editor_text = spell.source().to_owned();
+    }
+    state.loaded_spell = selected.clone();
+    state.preview = None;
+  }
+
   egui::Window::new("Spells").show(gui_ctx.ctx_mut(), |ui| {
     ui.horizontal(|ui| {
       let mut available_width = 500.;
@@ -38,9 +222,99 @@ pub fn spell_viewer(
       ui.group(|ui| {
         ui.allocate_space(EGVec2::new(available_width, 1.));
         ui.label("Editor");
-      });
 
-    });
+        let spell = selected
+          .as_ref()
+          .and_then(|name| spells.iter_mut().find(|s| s.name == *name));
+        if let Some(mut spell) = spell {
+          ui.label(egui::RichText::new(spell.name.as_str()).strong());
+          ui.label(spell.desc.as_str());
+          if let Some(budget) = spell.meta.mana_budget {
+            ui.label(format!("Mana budget: {}", budget));
+          }
+          if !spell.meta.forms.is_empty() {
+            ui.label(format!("Forms: {}", spell.meta.forms.join(", ")));
+            let missing = progression.missing(&spell.meta.forms);
+            if !missing.is_empty() {
+              ui.colored_label(
+                egui::Color32::RED,
+                format!("Can't be cast until {} is unlocked", missing.join(", ")),
+              );
+            }
+          }
+
+          let mut layouter = |ui: &egui::Ui, text: &str, wrap_width: f32| {
+            let error_line = wizard_lang::parse(text).err().map(|e| e.line);
+            let mut job = highlight(text, error_line);
+            job.wrap_width = wrap_width;
+            ui.fonts().layout_job(job)
+          };
+          ui.add(
+            egui::TextEdit::multiline(&mut state.editor_text)
+              .code_editor()
+              .desired_rows(12)
+              .desired_width(available_width)
+              .layouter(&mut layouter),
+          );
 
+          let parsed = wizard_lang::parse(&state.editor_text);
+          if let Err(e) = &parsed {
+            ui.colored_label(egui::Color32::RED, e.to_string());
+          }
+
+          ui.horizontal(|ui| {
+            let has_changes = state.editor_text != spell.source();
+            let can_save = has_changes && parsed.is_ok();
+            if ui.add_enabled(can_save, egui::Button::new("Save")).clicked() {
+              spell.set_source(state.editor_text.clone());
+            }
+            if ui
+              .add_enabled(has_changes, egui::Button::new("Revert"))
+              .clicked()
+            {
+              state.editor_text = spell.source().to_owned();
+            }
+            if ui
+              .add_enabled(parsed.is_ok(), egui::Button::new("Simulate"))
+              .on_hover_text("Run the spell on a copy of memory, without using a turn")
+              .clicked()
+            {
+              if let Ok(ast) = &parsed {
+                // simulate the wizard casting it right now
+                let wizard = sim.players.iter().find(|(_, p)| p.magika.is_some());
+                let magika = wizard.and_then(|(_, p)| p.magika).unwrap_or(0.);
+                let caster = wizard.map(|(e, _)| e);
+                let options = RunOptions {
+                  step_limit: step_budget(&spell, magika),
+                  caster,
+                  trace: true,
+                };
+                // run what would be saved, not the spell as it was last saved
+                let run = dry_run(
+                  &Bytecode::compile(ast),
+                  options,
+                  &mut sim.memory.clone(),
+                  &sim.map,
+                  &sim.layout,
+                  &sim.forms,
+                  &sim.costs,
+                );
+                latest_trace.0 = Some(RecordedTrace::new(&spell.name, &state.editor_text, &run));
+                state.preview = Some((state.editor_text.clone(), run));
+              }
+            }
+          });
+
+          if let Some((source, run)) = &state.preview {
+            show_preview(ui, &spell, run, *source != state.editor_text);
+          }
+          if let Ok(program) = spell.compiled() {
+            show_bytecode(ui, program);
+          }
+        } else {
+          ui.label("Choose a spell to edit it.");
+        }
+      });
+    });
   });
 }
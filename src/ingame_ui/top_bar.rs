@@ -2,9 +2,10 @@ use bevy::prelude::*;
 use bevy_egui::{egui, EguiContext, egui::Vec2 as EGVec2};
 
 use crate::{constants};
-use crate::turn::CompletedTurn;
+use crate::turn::{CompletedTurn, TurnLog};
 
 use super::memory_viewer::MemoryWindowState;
+use super::spell_console::SpellConsoleState;
 use super::spell_viewer::SpellViewerState;
 
 // TODO: the side bar sends events that are handled by other systems, rather
@@ -14,13 +15,16 @@ pub fn top_bar(
   mut commands: Commands,
   mut gui: ResMut<EguiContext>,
   turns_elapsed: Query<&CompletedTurn>,
+  turn_log: Res<TurnLog>,
   memory_window_state: Query<(Entity, &MemoryWindowState)>,
   spell_window_state: Query<(Entity, &SpellViewerState)>,
+  console_state: Query<(Entity, &SpellConsoleState)>,
 ) {
   let egui_height = gui.ctx_mut().available_rect().height() * constants::TOP_BAR_DESIRED_SIZE;
 
   let is_spells_shown = spell_window_state.get_single().is_ok();
   let is_memory_shown = memory_window_state.get_single().is_ok();
+  let is_console_shown = console_state.get_single().is_ok();
 
   let mut top_bar_background = egui::Frame::default();
   top_bar_background.fill = egui::Color32::BLACK;
@@ -44,7 +48,18 @@ pub fn top_bar(
         //ui.heading(constants::GAME_NAME);
 
         ui.add_space(50.);
-        ui.label(format!("turn {}", turns_elapsed.iter().count()));
+        // hovering over the turn shows what happened in the last one
+        let turn = turns_elapsed.iter().count();
+        let last_turn: Vec<&str> = turn_log
+          .entries
+          .iter()
+          .filter(|(t, _)| *t == turn)
+          .map(|(_, entry)| entry.as_str())
+          .collect();
+        let turn_label = ui.label(format!("turn {}", turn));
+        if !last_turn.is_empty() {
+          turn_label.on_hover_text(last_turn.join("\n"));
+        }
 
         let spells_button_text = if is_spells_shown {
           "Hide Spells"
@@ -74,6 +89,20 @@ pub fn top_bar(
           }
         }
 
+        let console_button_text = if is_console_shown {
+          "Hide Console"
+        } else {
+          "Show Console"
+        };
+        if ui.button(console_button_text).clicked() {
+          if !is_console_shown {
+            commands.spawn().insert(SpellConsoleState);
+          } else {
+            let (entity, _) = console_state.single();
+            commands.entity(entity).despawn_recursive();
+          }
+        }
+
         if ui.button("Quit").clicked() {
           commands.spawn().insert(crate::level::RoundSummary(crate::level::RoundResult::Neutral));
         }
@@ -2,6 +2,7 @@ use bevy::prelude::*;
 
 use crate::{
   map_entities::{enemy::Enemy, player::PlayerStatus, EntityHealth},
+  spells::{wizard_conflicts::ConflictPolicy, wizard_progression::Progression},
   turn::CompletedTurn,
   GameState,
 };
@@ -10,20 +11,34 @@ use crate::{
 pub struct AvailableLevel {
   pub name: String,
   pub ldtk_id: usize,
+  /// The form that is unlocked when the level is beaten
+  pub reward: Option<String>,
+  /// How casts that write to the same memory in one turn are settled
+  pub conflict_policy: ConflictPolicy,
 }
 
-impl From<&(&'static str, usize)> for AvailableLevel {
-  fn from(d: &(&'static str, usize)) -> Self {
+type LevelData = (&'static str, usize, Option<&'static str>, ConflictPolicy);
+
+impl From<&LevelData> for AvailableLevel {
+  fn from(d: &LevelData) -> Self {
     Self {
       name: d.0.to_owned(),
       ldtk_id: d.1,
+      reward: d.2.map(str::to_owned),
+      conflict_policy: d.3,
     }
   }
 }
 
 // TODO: in an ideal world, this information would be stored seperately
 pub fn level_startup(mut commands: Commands) {
-  [("Square Level", 2), ("Watery Level", 0), ("Bridge Level", 1)].iter().for_each(|d| {
+  [
+    ("Square Level", 2, Some("Tile"), ConflictPolicy::Priority),
+    ("Watery Level", 0, Some("Enemy"), ConflictPolicy::LastWriter),
+    ("Bridge Level", 1, None, ConflictPolicy::Cancel),
+  ]
+  .iter()
+  .for_each(|d| {
     let av = AvailableLevel::from(d);
     commands.spawn().insert(av);
   })
@@ -67,6 +82,30 @@ pub fn end_round(
   }
 }
 
+/// Unlocks the forms that levels give as rewards, the first time they are beaten
+pub fn grant_level_rewards(
+  mut progression: ResMut<Progression>,
+  completed: Query<&AvailableLevel, Added<CompletedLevel>>,
+) {
+  for level in completed.iter() {
+    if let Some(form) = &level.reward {
+      if progression.unlock(form) {
+        info!("unlocked the {} form", form);
+      }
+    }
+  }
+}
+
+/// Casts are settled the way the level that is being played says
+pub fn apply_conflict_policy(
+  mut policy: ResMut<ConflictPolicy>,
+  started: Query<&AvailableLevel, Added<CurrentLevel>>,
+) {
+  if let Ok(level) = started.get_single() {
+    *policy = level.conflict_policy;
+  }
+}
+
 pub fn return_to_menu(
   mut commands: Commands,
   mut events: EventReader<ToMenu>,
@@ -101,6 +140,8 @@ impl Plugin for LevelPlugin {
     app
       .add_startup_system(level_startup)
       .add_system(end_round)
+      .add_system(grant_level_rewards)
+      .add_system(apply_conflict_policy)
       .add_system(return_to_menu)
       .add_event::<ToMenu>();
   }
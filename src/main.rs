@@ -11,6 +11,7 @@ mod level;
 mod map;
 mod map_entities;
 mod menu;
+mod pathfinding;
 mod spells;
 mod turn;
 mod utils;
@@ -53,6 +54,7 @@ fn main() {
     .add_plugin(ingame_ui::UIPlugin)
     .add_plugin(map::MapPlugin)
     .add_plugin(map_entities::MapEntityPlugin)
+    .add_plugin(pathfinding::PathfindingPlugin)
     .add_plugin(turn::TurnPlugin)
     .add_plugin(spells::SpellsPlugin)
     .add_plugin(level::LevelPlugin)
@@ -83,7 +83,7 @@ pub struct Open;
 
 #[derive(Component, Default)]
 pub struct Wall {
-  health: f32,
+  pub health: f32,
 }
 
 #[derive(Component, Default)]
@@ -347,9 +347,15 @@ pub fn click_tile(
 fn map_pan(
   time: Res<Time>,
   keys: Res<Input<KeyCode>>,
+  key_status: Res<crate::ingame_ui::KeyboardStatus>,
   mut camera: Query<&mut Transform, With<MainCamera>>,
   layers: Query<&Layer>,
 ) {
+  // Don't pan the map while the player is typing
+  if key_status.disregard_key_event() {
+    return;
+  }
+
   let (scroll_limit_plus_x, scroll_limit_plus_y) = layers
     .iter()
     .next()
@@ -1,12 +1,22 @@
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
-use bevy_ecs_tilemap::{MapQuery, TileParent, TilePos};
-use std::collections::{HashMap, HashSet};
+use bevy_ecs_tilemap::{TileParent, TilePos};
+use std::cmp::Ordering;
+use std::collections::HashSet;
 
 use super::{player::PlayerStatus, EntityHealth, MapEntityType};
 
-use crate::map::{DataLayer, DrawOnMap, TileKind};
+use crate::map::DrawOnMap;
+use crate::pathfinding::{self, NavGrid};
+use crate::spells::wizard_costs::ManaCostTable;
+use crate::spells::wizard_memory::MemoryBlob;
+use crate::spells::wizard_progression::EnemyMemoryMap;
+use crate::spells::wizard_serialize::{MemoryLayout, RecordKind};
+use crate::spells::wizard_types::FormRegistry;
+use crate::spells::{dry_run, step_budget, AvailableSpell, RunOptions, SpellRun};
 use crate::turn::{
-  EnemyTurnAnimating, EntityAction, EntityPendingAction, PendingAttack, TurnDisplayer,
+  EnemyTurnAnimating, EntityAction, EntityPendingAction, PendingAttack, PendingMove,
+  TurnDisplayer,
 };
 use crate::utils;
 use crate::constants;
@@ -18,12 +28,53 @@ pub enum EnemyAIType {
   AttackWeakest,
   AttackClosest,
   RunAway,
+  /// Casts a spell when one would hurt the players or heal the enemies,
+  /// and otherwise attacks the closest player
+  CastSpells,
+}
+
+impl EnemyAIType {
+  /// Matches the variant names, as they are written in LDtk
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "StayPut" => Some(EnemyAIType::StayPut),
+      "AttackUntilWeak" => Some(EnemyAIType::AttackUntilWeak),
+      "AttackWeakest" => Some(EnemyAIType::AttackWeakest),
+      "AttackClosest" => Some(EnemyAIType::AttackClosest),
+      "RunAway" => Some(EnemyAIType::RunAway),
+      "CastSpells" => Some(EnemyAIType::CastSpells),
+      _ => None,
+    }
+  }
 }
 
 #[derive(Component)]
 pub struct Enemy {
   ai_type: EnemyAIType,
-  speed: u32,
+  pub speed: u32,
+  /// Only enemy spellcasters have magika, just like only the wizard does
+  pub magika: Option<f32>,
+  pub max_magika: f32,
+  /// The names of the spells this enemy can cast
+  pub spells: Vec<String>,
+}
+
+impl Enemy {
+  /// A rival wizard, that casts the given spells
+  pub fn caster(magika: f32, spells: Vec<String>) -> Self {
+    Self {
+      ai_type: EnemyAIType::CastSpells,
+      magika: Some(magika),
+      max_magika: magika,
+      spells,
+      ..Default::default()
+    }
+  }
+
+  pub fn with_ai(mut self, ai_type: EnemyAIType) -> Self {
+    self.ai_type = ai_type;
+    self
+  }
 }
 
 impl Default for Enemy {
@@ -31,6 +82,9 @@ impl Default for Enemy {
     Self {
       ai_type: EnemyAIType::AttackClosest,
       speed: constants::ENEMY_DEFAULT_MOVE_SPEED,
+      magika: None,
+      max_magika: 0.,
+      spells: vec![],
     }
   }
 }
@@ -48,200 +102,49 @@ pub struct NewEnemyBundle {
   pub action: EntityPendingAction,
 }
 
-#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
-struct HashablePos(i32, i32);
-
-impl HashablePos {
-  fn up(&self) -> Self {
-    *self + HashablePos(0, 1)
-  }
-  fn down(&self) -> Self {
-    *self + HashablePos(0, -1)
-  }
-  fn left(&self) -> Self {
-    *self + HashablePos(1, 0)
-  }
-  fn right(&self) -> Self {
-    *self + HashablePos(-1, 0)
-  }
-  fn is_on_map(&self, dim: (u32, u32)) -> bool {
-    0 < self.0 && self.0 < dim.0 as i32 && 1 < self.1 && self.1 < dim.1 as i32
-  }
-}
-
-impl std::ops::Add for HashablePos {
-  type Output = Self;
-
-  fn add(self, other: Self) -> Self {
-    HashablePos(self.0 + other.0, self.1 + other.1)
-  }
-}
-
-impl From<&TilePos> for HashablePos {
-  fn from(p: &TilePos) -> Self {
-    Self(p.0 as i32, p.1 as i32)
-  }
-}
-
-impl Into<TilePos> for HashablePos {
-  fn into(self) -> TilePos {
-    TilePos(self.0 as u32, self.1 as u32)
-  }
-}
-
-#[derive(Clone, Debug)]
-struct PathfindingNode {
-  pos: HashablePos,
-  last_pos: HashablePos,
-  from_start: i32,
-  to_goal: i32,
-  visited: bool,
-}
-
-impl PathfindingNode {
-  fn weight(&self) -> i32 {
-    self.from_start + self.to_goal
-  }
-}
-
-fn get_tile_distance(cur: HashablePos, goal: HashablePos) -> i32 {
-  (goal.0 - cur.0).abs() + (goal.1 - cur.1).abs()
-}
-
-// Returns a list of tile positions,
-// showing the path to the closest adjacent point to the goal.
-fn simple_a_star(
-  start: HashablePos,
-  goal: HashablePos,
-  blocked_tiles: HashSet<HashablePos>,
-  map_dim: (u32, u32),
-) -> Option<Vec<HashablePos>> {
-  let mut open_tiles: HashMap<HashablePos, PathfindingNode> = HashMap::new();
-  open_tiles.insert(
-    start,
-    PathfindingNode {
-      pos: start,
-      last_pos: start,
-      from_start: 0,
-      to_goal: get_tile_distance(start, goal),
-      visited: true,
-    },
-  );
-
-  let mut cur = start;
-
-  'outer: loop {
-    let new_positions: Vec<PathfindingNode> = [cur.up(), cur.down(), cur.left(), cur.right()]
-      .iter()
-      .filter(|pos| pos.is_on_map(map_dim))
-      .filter(|pos| !blocked_tiles.contains(pos))
-      .map(|pos| PathfindingNode {
-        pos: pos.to_owned(),
-        last_pos: cur.clone(),
-        from_start: get_tile_distance(*pos, start),
-        to_goal: get_tile_distance(*pos, goal),
-        visited: false,
-      })
-      .collect();
-
-    for mut node in new_positions.into_iter() {
-      let node_pos = node.pos;
-      if node.to_goal == 1 {
-        open_tiles.insert(node_pos, node);
-        cur = node_pos;
-        break 'outer;
-      }
-      if let Some(old_node) = open_tiles.get(&node_pos) {
-        if node.weight() < old_node.weight()
-          || node.weight() == old_node.weight() && node.to_goal < old_node.to_goal
-        {
-          if old_node.visited {
-            node.visited = true;
-          }
-          open_tiles.insert(node_pos, node);
-        }
-      } else {
-        open_tiles.insert(node_pos.clone(), node);
-      }
-    }
-
-    let mut best: Option<PathfindingNode> = None;
-    for (_, node) in open_tiles.iter() {
-      if !node.visited {
-        if let Some(cur_best) = best.as_ref() {
-          if node.weight() < cur_best.weight() {
-            best = Some(node.clone());
-          }
-        } else {
-          best = Some(node.clone());
-        }
-      }
-    }
-
-    if let Some(new_cur) = best {
-      cur = new_cur.pos;
-      open_tiles.get_mut(&cur).unwrap().visited = true;
-    } else {
-      // There are no spaces that haven't been visited
-      // so no path can be found.
-      return None;
-    }
-  }
-
-  let mut steps: Vec<HashablePos> = vec![];
-  let mut last = cur;
-  while last != start {
-    let previous_node = open_tiles.get(&last).unwrap();
-    steps.push(previous_node.pos);
-    last = previous_node.last_pos;
-  }
-  steps.reverse();
-  Some(steps)
-}
-
+/// The path the enemy walks towards `goal` this turn, and whether it ends next to `goal`.
+/// `claimed` holds tiles other enemies are moving to.
 fn get_move(
   start: &TilePos,
   goal: &TilePos,
   speed: u32,
-  blocked: &Vec<TilePos>,
-  map_dim: (u32, u32),
-) -> Option<(TilePos, bool)> {
+  grid: &NavGrid,
+  claimed: &HashSet<TilePos>,
+) -> Option<(Vec<TilePos>, bool)> {
   if start == goal {
     return None;
   }
 
   if utils::tile_distance(start, goal) == 1 {
-    return Some((start.to_owned(), true));
+    return Some((vec![], true));
   }
 
-  let mut blocked_set = HashSet::new();
-  blocked.into_iter().map(HashablePos::from).for_each(|p| {
-    blocked_set.insert(p);
-  });
-
-  simple_a_star(start.into(), goal.into(), blocked_set, map_dim)
-    .map(|mut path| {
-      if speed > path.len() as u32 {
-        path.pop().unwrap().into()
-      } else {
-        path[((speed - 1) as usize)].into()
-      }
-    })
-    .map(|pos| {
-      (pos, utils::tile_distance(&pos, goal) == 1)
-    })
+  pathfinding::path_next_to(*start, *goal, grid.map_dim(), |p| {
+    grid.is_walkable(p) && !claimed.contains(p)
+  })
+  .filter(|path| !path.is_empty() && speed > 0)
+  .map(|mut path| {
+    // go as far along the path as the enemy's speed allows
+    path.truncate(speed as usize);
+    let reached_target = path
+      .last()
+      .is_some_and(|end| utils::tile_distance(end, goal) == 1);
+    (path, reached_target)
+  })
 }
 
 fn run_in_direction(
   pos: &TilePos,
   speed: u32,
-  map_dim: (u32, u32),
   direction: (i32, i32),
-  blocked: &Vec<TilePos>,
+  grid: &NavGrid,
+  claimed: &HashSet<TilePos>,
 ) -> EntityAction {
+  let map_dim = grid.map_dim();
   let i_map_dim = (map_dim.0 as i32, map_dim.1 as i32);
   let mut new_x = pos.0 as i32;
   let mut new_y = pos.1 as i32;
+  let mut path = vec![];
   for _ in 1..speed {
     let test_x = new_x + direction.0 * 1;
     let test_y = new_y + direction.1 * 1;
@@ -253,38 +156,108 @@ fn run_in_direction(
       break;
     }
 
-    if blocked
-      .iter()
-      .find(|p| p.0 as i32 == test_x && p.1 as i32 == test_y)
-      .is_some()
-    {
+    let test_pos = TilePos(test_x as u32, test_y as u32);
+    if !grid.is_walkable(&test_pos) || claimed.contains(&test_pos) {
       break;
     }
 
     new_x = test_x;
     new_y = test_y;
+    path.push(test_pos);
   }
-  let new_pos = TilePos(new_x as u32, new_y as u32);
-  if utils::tile_distance(pos, &new_pos) == 0 {
+  if path.is_empty() {
     EntityAction::Wait
   } else {
-    EntityAction::Move((pos.to_owned(), new_pos))
+    EntityAction::Move(PendingMove::new(pos.to_owned(), path))
+  }
+}
+
+/// How much a spell would help the enemies: damage done to players, plus health given to enemies
+fn spell_benefit(run: &SpellRun) -> f32 {
+  run
+    .changes
+    .iter()
+    .filter(|change| change.field == "health")
+    .map(|change| {
+      let gained = change.new as f32 - change.old as f32;
+      match change.kind {
+        RecordKind::Player => -gained,
+        RecordKind::Enemy => gained,
+        RecordKind::Tile => 0.,
+      }
+    })
+    .sum()
+}
+
+/// What enemy spellcasters need to try out their spells
+#[derive(SystemParam)]
+pub struct SpellSimulation<'w, 's> {
+  spells: Query<'w, 's, &'static AvailableSpell>,
+  memory: Res<'w, MemoryBlob>,
+  map: Res<'w, EnemyMemoryMap>,
+  layout: Res<'w, MemoryLayout>,
+  forms: Res<'w, FormRegistry>,
+  costs: Res<'w, ManaCostTable>,
+}
+
+/// Simulates every spell the enemy knows, and picks the one that helps the most.
+/// Spells that would fail, or don't help at all, are never chosen.
+/// `scratch` is a copy of memory that the simulations run in.
+fn choose_spell(
+  caster: Entity,
+  enemy: &Enemy,
+  sim: &SpellSimulation,
+  scratch: &mut MemoryBlob,
+) -> Option<String> {
+  let magika = enemy.magika?;
+  if magika < constants::SPELL_MAGIKA_COST {
+    return None;
+  }
+
+  let mut best: Option<(&AvailableSpell, f32)> = None;
+  for spell in sim.spells.iter().filter(|s| enemy.spells.contains(&s.name)) {
+    let program = match spell.compiled() {
+      Ok(program) => program,
+      Err(_) => continue,
+    };
+    let options = RunOptions {
+      step_limit: step_budget(spell, magika),
+      caster: Some(caster),
+      trace: false,
+    };
+    let run = dry_run(
+      program,
+      options,
+      scratch,
+      &sim.map.0,
+      &sim.layout,
+      &sim.forms,
+      &sim.costs,
+    );
+    if run.fizzled() || run.total_cost() > magika || !run.within_budget(spell) {
+      continue;
+    }
+    let benefit = spell_benefit(&run);
+    if benefit > 0. && best.is_none_or(|(_, best_benefit)| benefit > best_benefit) {
+      best = Some((spell, benefit));
+    }
   }
+  best.map(|(spell, _)| spell.name.clone())
 }
 
 /// Creates the correct actions for enemies after the player turn has ended
 pub fn enemy_ai(
   run_ai: Query<&TurnDisplayer, Added<EnemyTurnAnimating>>,
   mut enemies: Query<(
+    Entity,
     &mut EntityPendingAction,
     &Enemy,
     &EntityHealth,
     &TilePos,
-    &TileParent,
   )>,
-  players: Query<(Entity, &TilePos, &PlayerStatus)>,
-  tiles: Query<(&TilePos, &DataLayer)>,
-  mut map_q: MapQuery,
+  players: Query<(Entity, &TilePos, &PlayerStatus, &EntityHealth)>,
+  grid: Res<NavGrid>,
+  sim: SpellSimulation,
 ) {
   // An enemy turn has started when there is both a TurnDisplayer and a EnemyTurnAnimating component added
   if let Err(_) = run_ai.get_single() {
@@ -292,20 +265,15 @@ pub fn enemy_ai(
   }
   // ok, the enemy turn has started
 
-  // make a list of all the blocked points to use in pathfinding. This seems inefficient,
-  // but the borrows on MapQuery and Query<&TilePos> are really hard to figure out.
-  let mut blocked_points: Vec<TilePos> = tiles
-    .iter()
-    .filter_map(|(tp, dl)| match dl.kind {
-      TileKind::Floor => None,
-      _ => Some(tp.to_owned()),
-    })
-    .collect();
+  // Tiles that enemies have chosen to move to or stay on this turn.
+  // Everything else that is in the way is in the `NavGrid`.
+  let mut claimed: HashSet<TilePos> = HashSet::new();
+  // only copied if an enemy casts spells, and shared by all of them
+  let mut scratch: Option<MemoryBlob> = None;
 
   let mut warrior_pos: (Entity, TilePos) = (Entity::from_raw(0), Default::default());
   let mut wizard_pos: (Entity, TilePos) = (Entity::from_raw(0), Default::default());
   for p in players.iter() {
-    blocked_points.push(p.1.to_owned());
     if p.2.magika.is_some() {
       wizard_pos = (p.0, p.1.to_owned());
     } else {
@@ -313,11 +281,7 @@ pub fn enemy_ai(
     }
   }
 
-  for (mut queued_action, enemy_data, enemy_health, enemy_pos, parent) in enemies.iter_mut() {
-    let layer = map_q.get_layer(parent.map_id, parent.layer_id).unwrap().1;
-    let ms = layer.get_layer_size_in_tiles();
-    let map_dim = (ms.0, ms.1);
-
+  for (entity, mut queued_action, enemy_data, enemy_health, enemy_pos) in enemies.iter_mut() {
     let mut ai_type = enemy_data.ai_type;
     if ai_type == EnemyAIType::AttackUntilWeak {
       if enemy_health.health < 20. {
@@ -327,6 +291,13 @@ pub fn enemy_ai(
       }
     }
 
+    let mut spell = None;
+    if ai_type == EnemyAIType::CastSpells {
+      let scratch = scratch.get_or_insert_with(|| sim.memory.clone());
+      spell = choose_spell(entity, enemy_data, &sim, scratch);
+      ai_type = EnemyAIType::AttackClosest;
+    }
+
     let closest_player = if utils::tile_distance(enemy_pos, &warrior_pos.1)
       > utils::tile_distance(enemy_pos, &wizard_pos.1)
     {
@@ -335,36 +306,65 @@ pub fn enemy_ai(
       warrior_pos
     };
 
+    // the player with the least health, or the closest one if they are tied
+    let weakest_player = players
+      .iter()
+      .min_by(|a, b| {
+        a.3
+          .health
+          .partial_cmp(&b.3.health)
+          .unwrap_or(Ordering::Equal)
+          .then_with(|| {
+            utils::tile_distance(enemy_pos, a.1).cmp(&utils::tile_distance(enemy_pos, b.1))
+          })
+      })
+      .map_or(closest_player, |p| (p.0, p.1.to_owned()));
+
+    // AttackWeakest enemies go after the weakest player, everything else the closest one
+    let target = if ai_type == EnemyAIType::AttackWeakest {
+      weakest_player
+    } else {
+      closest_player
+    };
+
     queued_action.is_ready = true;
 
-    // If an enemy is next to a player, the enemy will always attack
-    if utils::tile_distance(&closest_player.1, enemy_pos) == 1 {
+    // A spell is only chosen when it helps, so it comes before anything else.
+    // Otherwise, if an enemy is next to its target, the enemy will always attack
+    if let Some(spell) = spell {
+      queued_action.action = EntityAction::Cast(spell);
+    } else if utils::tile_distance(&target.1, enemy_pos) == 1 {
       queued_action.action = EntityAction::Attack(PendingAttack::new(
-        closest_player.0,
-        closest_player.1.to_owned(),
+        target.0,
+        target.1.to_owned(),
         enemy_pos.to_owned(),
         enemy_pos.to_owned(),
       ));
     } else {
       match ai_type {
-        EnemyAIType::AttackClosest => {
-          if let Some((new_pos, reached_target)) = get_move(
+        EnemyAIType::AttackClosest | EnemyAIType::AttackWeakest => {
+          if let Some((path, reached_target)) = get_move(
             enemy_pos,
-            &closest_player.1,
+            &target.1,
             enemy_data.speed,
-            &blocked_points,
-            map_dim,
+            &grid,
+            &claimed,
           ) {
-            blocked_points.push(new_pos);
+            let new_pos = path.last().copied().unwrap_or(*enemy_pos);
+            claimed.insert(new_pos);
             if reached_target {
-              queued_action.action = EntityAction::Attack(PendingAttack::new(
-                closest_player.0,
-                closest_player.1.clone(),
-                new_pos,
-                enemy_pos.to_owned(),
-              ));
+              queued_action.action = EntityAction::Attack(
+                PendingAttack::new(
+                  target.0,
+                  target.1.clone(),
+                  new_pos,
+                  enemy_pos.to_owned(),
+                )
+                .with_path(path),
+              );
             } else {
-              queued_action.action = EntityAction::Move((enemy_pos.to_owned(), new_pos));
+              queued_action.action =
+                EntityAction::Move(PendingMove::new(enemy_pos.to_owned(), path));
             }
           } else {
             // a path was not found, so just wait
@@ -378,33 +378,33 @@ pub fn enemy_ai(
             queued_action.action = run_in_direction(
               enemy_pos,
               enemy_data.speed,
-              map_dim,
               (0, -1),
-              &blocked_points,
+              &grid,
+              &claimed,
             );
           } else if warrior_pos.1 < enemy_pos.1 {
             queued_action.action = run_in_direction(
               enemy_pos,
               enemy_data.speed,
-              map_dim,
               (0, 1),
-              &blocked_points,
+              &grid,
+              &claimed,
             );
           } else if warrior_pos.0 > enemy_pos.0 {
             queued_action.action = run_in_direction(
               enemy_pos,
               enemy_data.speed,
-              map_dim,
               (-1, 0),
-              &blocked_points,
+              &grid,
+              &claimed,
             );
           } else if warrior_pos.0 < enemy_pos.0 {
             queued_action.action = run_in_direction(
               enemy_pos,
               enemy_data.speed,
-              map_dim,
               (1, 0),
-              &blocked_points,
+              &grid,
+              &claimed,
             );
           } else {
             queued_action.action = EntityAction::Wait;
@@ -418,7 +418,7 @@ pub fn enemy_ai(
     }
 
     if !(queued_action.is_move() || queued_action.is_attack()) {
-      blocked_points.push(enemy_pos.to_owned());
+      claimed.insert(enemy_pos.to_owned());
     }
   }
 }
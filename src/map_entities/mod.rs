@@ -41,15 +41,79 @@ impl From<EntityInstance> for MapEntityType {
   }
 }
 
+/// Settings for enemy spellcasters, read from the `Magika` and `Spells` fields of an LDtk entity.
+/// Enemies without any spells are ordinary enemies.
+#[derive(Component, Debug, Default, Clone)]
+pub struct CasterFields {
+  pub magika: Option<f32>,
+  pub spells: Vec<String>,
+}
+
+impl From<EntityInstance> for CasterFields {
+  fn from(instance: EntityInstance) -> Self {
+    let mut fields = Self::default();
+    for field in instance.field_instances.iter() {
+      match (field.identifier.as_str(), &field.value) {
+        ("Magika", FieldValue::Float(magika)) => fields.magika = *magika,
+        ("Magika", FieldValue::Int(magika)) => fields.magika = magika.map(|m| m as f32),
+        ("Spells", FieldValue::Strings(spells)) => {
+          fields.spells = spells.iter().flatten().cloned().collect()
+        }
+        // a single field, with the spell names separated by commas
+        ("Spells", FieldValue::String(Some(spells))) => {
+          fields.spells = spells
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_owned)
+            .collect()
+        }
+        _ => {}
+      }
+    }
+    fields
+  }
+}
+
 #[derive(Component, Debug)]
 pub struct EntityHealth {
   pub health: f32,
+  /// Spells can't heal past this
+  pub max: f32,
 }
 
 impl Default for EntityHealth {
   fn default() -> Self {
     Self {
       health: constants::PLAYER_MAX_HEALTH,
+      max: constants::PLAYER_MAX_HEALTH,
+    }
+  }
+}
+
+/// The AI an enemy uses, read from the `AI` field of an LDtk entity.
+/// Enemies without one use the default for their kind.
+#[derive(Component, Debug, Default, Clone)]
+pub struct AIField(pub Option<enemy::EnemyAIType>);
+
+impl From<EntityInstance> for AIField {
+  fn from(instance: EntityInstance) -> Self {
+    let name = instance
+      .field_instances
+      .iter()
+      .find_map(|field| match (field.identifier.as_str(), &field.value) {
+        ("AI", FieldValue::Enum(Some(name))) | ("AI", FieldValue::String(Some(name))) => Some(name),
+        _ => None,
+      });
+    match name {
+      Some(name) => {
+        let ai = enemy::EnemyAIType::from_name(name);
+        if ai.is_none() {
+          warn!("{} has an unknown AI {:?}", instance.identifier, name);
+        }
+        Self(ai)
+      }
+      None => Self(None),
     }
   }
 }
@@ -58,6 +122,10 @@ impl Default for EntityHealth {
 pub struct MapEntityStart {
   #[from_entity_instance]
   start: MapEntityType,
+  #[from_entity_instance]
+  caster: CasterFields,
+  #[from_entity_instance]
+  ai: AIField,
   #[grid_coords]
   pos: GridCoords,
 }
@@ -66,11 +134,14 @@ pub struct MapEntityStart {
 // working out how to use it's macros.
 pub fn spawn_entities_on_map(
   mut commands: Commands,
-  entity_start: Query<(&GridCoords, &MapEntityType), Added<MapEntityType>>,
+  entity_start: Query<
+    (&GridCoords, &MapEntityType, &CasterFields, &AIField),
+    Added<MapEntityType>,
+  >,
   data_tiles: Query<(Entity, &TilePos, &TileParent), With<DataLayer>>,
 ) {
   for (tile_entity, tile_pos, tile_parent) in data_tiles.iter() {
-    for (coords, entity_type) in entity_start.iter() {
+    for (coords, entity_type, caster, ai) in entity_start.iter() {
       if coords.x as u32 == tile_pos.0 && coords.y as u32 == tile_pos.1 {
         match entity_type {
           &MapEntityType::Player(player_id) => {
@@ -102,7 +173,19 @@ pub fn spawn_entities_on_map(
             commands.entity(tile_entity).insert(map::TileHasEntity);
           }
           &MapEntityType::Enemy => {
+            let mut enemy = if caster.spells.is_empty() {
+              enemy::Enemy::default()
+            } else {
+              enemy::Enemy::caster(
+                caster.magika.unwrap_or(constants::ENEMY_DEFAULT_MAGIKA),
+                caster.spells.clone(),
+              )
+            };
+            if let AIField(Some(ai)) = *ai {
+              enemy = enemy.with_ai(ai);
+            }
             commands.spawn_bundle(enemy::NewEnemyBundle {
+              enemy,
               map_pos: tile_pos.to_owned(),
               parent: tile_parent.to_owned(),
               map_entity_type: entity_type.to_owned(),
@@ -140,7 +223,7 @@ impl Plugin for MapEntityPlugin {
       .register_ldtk_entity::<MapEntityStart>("Player2Start")
       .register_ldtk_entity::<MapEntityStart>("EnemyStart")
       .add_system(spawn_entities_on_map)
-      .add_system(enemy::enemy_ai)
+      .add_system(enemy::enemy_ai.after("refresh-memory-image").after("nav-grid"))
       .add_system_set(SystemSet::on_exit(GameState::Running).with_system(unload_entities));
   }
 }
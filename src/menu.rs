@@ -77,13 +77,18 @@ pub fn quick_level_ui(
       ui.add_space(10.);
       for (level_entity, level, is_complete) in levels.iter() {
         ui.horizontal(|ui| {
-          if ui.button(level.name.as_str()).clicked() {
+          let button = ui
+            .button(level.name.as_str())
+            .on_hover_text(format!("When spells clash, {}", level.conflict_policy.describe()));
+          if button.clicked() {
             game_state.set(GameState::Running).unwrap();
             commands.entity(level_entity).insert(CurrentLevel);
           }
           if is_complete.is_some() {
             // I love using emojis in my code!
             ui.label("✔️");
+          } else if let Some(reward) = &level.reward {
+            ui.label(format!("Unlocks the {} form", reward));
           }
         });
       }
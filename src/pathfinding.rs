@@ -0,0 +1,355 @@
+//! Finding paths across the map, shared by the enemy AI and the player's move choosers.
+//! Units can only move up, down, left, and right, and every step costs the same.
+use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+
+use bevy::prelude::*;
+use bevy_ecs_tilemap::TilePos;
+
+use crate::map::{DataLayer, TileKind};
+use crate::map_entities::EntityHealth;
+use crate::{utils, GameState};
+
+/// The tiles next to `pos` that are inside a map of size `map_dim`
+pub fn neighbours(pos: TilePos, map_dim: (u32, u32)) -> impl Iterator<Item = TilePos> {
+  [(0, 1), (0, -1), (1, 0), (-1, 0)]
+    .into_iter()
+    .filter_map(move |(dx, dy): (i64, i64)| {
+      let x = pos.0 as i64 + dx;
+      let y = pos.1 as i64 + dy;
+      if x < 0 || y < 0 || x >= map_dim.0 as i64 || y >= map_dim.1 as i64 {
+        None
+      } else {
+        Some(TilePos(x as u32, y as u32))
+      }
+    })
+}
+
+/// A tile waiting to be searched
+#[derive(PartialEq, Eq)]
+struct OpenTile {
+  /// The steps taken to get here, plus the least it could take to finish
+  estimate: u32,
+  cost: u32,
+  pos: TilePos,
+}
+
+impl Ord for OpenTile {
+  /// `BinaryHeap` pops the largest item, so the lowest estimate has to be the largest.
+  /// Ties go to the tile that is further along, and then to the position so the result is always the same.
+  fn cmp(&self, other: &Self) -> Ordering {
+    other
+      .estimate
+      .cmp(&self.estimate)
+      .then_with(|| self.cost.cmp(&other.cost))
+      .then_with(|| (other.pos.0, other.pos.1).cmp(&(self.pos.0, self.pos.1)))
+  }
+}
+
+impl PartialOrd for OpenTile {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+/// A* search for the shortest path from `start` to any tile `stop_distance` steps away from `goal`.
+/// The path doesn't include `start`, and every tile on it has to be walkable.
+fn search(
+  start: TilePos,
+  goal: TilePos,
+  stop_distance: u32,
+  map_dim: (u32, u32),
+  walkable: impl Fn(&TilePos) -> bool,
+) -> Option<Vec<TilePos>> {
+  let heuristic = |pos: &TilePos| utils::tile_distance(pos, &goal).saturating_sub(stop_distance);
+
+  let mut open = BinaryHeap::new();
+  // the cheapest known cost to get to each tile, and the tile it was reached from
+  let mut came_from: HashMap<TilePos, (u32, TilePos)> = HashMap::new();
+  came_from.insert(start, (0, start));
+  open.push(OpenTile {
+    estimate: heuristic(&start),
+    cost: 0,
+    pos: start,
+  });
+
+  while let Some(OpenTile { cost, pos, .. }) = open.pop() {
+    if utils::tile_distance(&pos, &goal) == stop_distance {
+      let mut path = vec![];
+      let mut current = pos;
+      while current != start {
+        path.push(current);
+        current = came_from[&current].1;
+      }
+      path.reverse();
+      return Some(path);
+    }
+    // a cheaper way to this tile was already searched
+    if came_from.get(&pos).is_some_and(|(best, _)| *best < cost) {
+      continue;
+    }
+
+    for next in neighbours(pos, map_dim).filter(|p| walkable(p)) {
+      let next_cost = cost + 1;
+      if came_from.get(&next).is_none_or(|(best, _)| next_cost < *best) {
+        came_from.insert(next, (next_cost, pos));
+        open.push(OpenTile {
+          estimate: next_cost + heuristic(&next),
+          cost: next_cost,
+          pos: next,
+        });
+      }
+    }
+  }
+  None
+}
+
+/// The shortest path from `start` to a tile next to `goal`, used to walk up to something and attack it.
+/// The path is empty if `start` is already next to `goal`.
+pub fn path_next_to(
+  start: TilePos,
+  goal: TilePos,
+  map_dim: (u32, u32),
+  walkable: impl Fn(&TilePos) -> bool,
+) -> Option<Vec<TilePos>> {
+  search(start, goal, 1, map_dim, walkable)
+}
+
+/// Every tile that can be walked to from `start` in at most `max_steps` steps,
+/// with the shortest path to each one (not including `start`).
+pub fn reachable_tiles(
+  start: TilePos,
+  max_steps: u32,
+  map_dim: (u32, u32),
+  walkable: impl Fn(&TilePos) -> bool,
+) -> HashMap<TilePos, Vec<TilePos>> {
+  // the steps it took to get to each tile, and the tile it was reached from
+  let mut came_from: HashMap<TilePos, (u32, TilePos)> = HashMap::new();
+  came_from.insert(start, (0, start));
+  let mut queue = VecDeque::from([start]);
+  while let Some(pos) = queue.pop_front() {
+    let steps = came_from[&pos].0;
+    if steps == max_steps {
+      continue;
+    }
+    for next in neighbours(pos, map_dim).filter(|p| walkable(p)) {
+      if let Entry::Vacant(entry) = came_from.entry(next) {
+        entry.insert((steps + 1, pos));
+        queue.push_back(next);
+      }
+    }
+  }
+
+  came_from
+    .keys()
+    .filter(|pos| **pos != start)
+    .map(|pos| {
+      let mut path = vec![];
+      let mut current = *pos;
+      while current != start {
+        path.push(current);
+        current = came_from[&current].1;
+      }
+      path.reverse();
+      (*pos, path)
+    })
+    .collect()
+}
+
+/// Which tiles of the current level can be walked on, and where the units are.
+/// Built as the level spawns, then kept up to date as units move and tiles change kind,
+/// so movement code doesn't have to look through every tile.
+#[derive(Default)]
+pub struct NavGrid {
+  map_dim: (u32, u32),
+  floor: HashSet<TilePos>,
+  units: HashMap<Entity, TilePos>,
+  occupied: HashSet<TilePos>,
+}
+
+impl NavGrid {
+  pub fn map_dim(&self) -> (u32, u32) {
+    self.map_dim
+  }
+
+  pub fn is_floor(&self, pos: &TilePos) -> bool {
+    self.floor.contains(pos)
+  }
+
+  pub fn is_occupied(&self, pos: &TilePos) -> bool {
+    self.occupied.contains(pos)
+  }
+
+  /// Floor without a unit on it
+  pub fn is_walkable(&self, pos: &TilePos) -> bool {
+    self.is_floor(pos) && !self.is_occupied(pos)
+  }
+
+  fn set_tile(&mut self, pos: TilePos, kind: &TileKind) {
+    self.map_dim = (self.map_dim.0.max(pos.0 + 1), self.map_dim.1.max(pos.1 + 1));
+    if *kind == TileKind::Floor {
+      self.floor.insert(pos);
+    } else {
+      self.floor.remove(&pos);
+    }
+  }
+
+  fn remove_unit(&mut self, entity: Entity) {
+    if let Some(old) = self.units.remove(&entity) {
+      // two units can't share a tile, but don't lose track if they somehow do
+      if !self.units.values().any(|pos| *pos == old) {
+        self.occupied.remove(&old);
+      }
+    }
+  }
+
+  /// Forgets every unit that `exists` says is gone
+  fn remove_missing_units(&mut self, exists: impl Fn(Entity) -> bool) {
+    let count = self.units.len();
+    self.units.retain(|entity, _| exists(*entity));
+    if self.units.len() != count {
+      self.occupied = self.units.values().copied().collect();
+    }
+  }
+
+  fn move_unit(&mut self, entity: Entity, pos: TilePos) {
+    self.remove_unit(entity);
+    self.units.insert(entity, pos);
+    self.occupied.insert(pos);
+  }
+}
+
+/// Adds tiles to the grid as the level spawns, and updates them when they change
+fn update_nav_tiles(
+  mut grid: ResMut<NavGrid>,
+  tiles: Query<(&TilePos, &DataLayer), Changed<DataLayer>>,
+) {
+  for (pos, data) in tiles.iter() {
+    grid.set_tile(*pos, &data.kind);
+  }
+}
+
+/// Units on the map, as opposed to tiles, which also have a `TilePos`
+type UnitFilter = (With<EntityHealth>, Without<DataLayer>);
+
+/// Moves units in the grid, and drops the ones that have been despawned.
+/// Despawns are found by checking every unit, not with `RemovedComponents`,
+/// so none are missed whichever system or frame they happen in.
+fn update_nav_units(
+  mut grid: ResMut<NavGrid>,
+  moved: Query<(Entity, &TilePos), (Changed<TilePos>, UnitFilter)>,
+  units: Query<Entity, UnitFilter>,
+) {
+  grid.remove_missing_units(|entity| units.get(entity).is_ok());
+  for (entity, pos) in moved.iter() {
+    grid.move_unit(entity, *pos);
+  }
+}
+
+fn clear_nav_grid(mut grid: ResMut<NavGrid>) {
+  *grid = NavGrid::default();
+}
+
+pub struct PathfindingPlugin;
+
+impl Plugin for PathfindingPlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .insert_resource(NavGrid::default())
+      .add_system_set(
+        SystemSet::on_update(GameState::Running)
+          .with_system(update_nav_tiles.label("nav-grid"))
+          .with_system(update_nav_units.label("nav-grid")),
+      )
+      .add_system_set(SystemSet::on_exit(GameState::Running).with_system(clear_nav_grid));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Every tile is walkable, except for `walls`
+  fn without(walls: &[(u32, u32)]) -> impl Fn(&TilePos) -> bool + '_ {
+    move |pos| !walls.contains(&(pos.0, pos.1))
+  }
+
+  fn positions(path: &[TilePos]) -> Vec<(u32, u32)> {
+    path.iter().map(|p| (p.0, p.1)).collect()
+  }
+
+  #[test]
+  fn despawned_units_leave_the_grid() {
+    let mut world = World::new();
+    world.insert_resource(NavGrid::default());
+    let mut stage = SystemStage::single_threaded().with_system(update_nav_units);
+    let health = EntityHealth {
+      health: 10.,
+      max: 10.,
+    };
+    let unit = world.spawn().insert_bundle((TilePos(2, 3), health)).id();
+    stage.run(&mut world);
+    assert!(world.get_resource::<NavGrid>().unwrap().is_occupied(&TilePos(2, 3)));
+
+    // the removal isn't seen by the system, as if it happened after it ran
+    world.despawn(unit);
+    world.clear_trackers();
+    stage.run(&mut world);
+    assert!(!world.get_resource::<NavGrid>().unwrap().is_occupied(&TilePos(2, 3)));
+  }
+
+  #[test]
+  fn straight_corridor() {
+    let path = path_next_to(TilePos(0, 0), TilePos(4, 0), (5, 1), without(&[])).unwrap();
+    assert_eq!(positions(&path), vec![(1, 0), (2, 0), (3, 0)]);
+  }
+
+  #[test]
+  fn detour_around_a_wall() {
+    //  . . .
+    //  . # .
+    //  S # G
+    let walls = [(1, 0), (1, 1)];
+    let path = path_next_to(TilePos(0, 0), TilePos(2, 0), (3, 3), without(&walls)).unwrap();
+    assert_eq!(
+      positions(&path),
+      vec![(0, 1), (0, 2), (1, 2), (2, 2), (2, 1)]
+    );
+  }
+
+  #[test]
+  fn unreachable_goal() {
+    let walls = [(1, 0), (1, 1), (1, 2)];
+    assert_eq!(
+      path_next_to(TilePos(0, 0), TilePos(2, 0), (3, 3), without(&walls)),
+      None
+    );
+  }
+
+  #[test]
+  fn already_next_to_the_goal() {
+    let path = path_next_to(TilePos(1, 1), TilePos(1, 2), (3, 3), without(&[])).unwrap();
+    assert!(path.is_empty());
+  }
+
+  #[test]
+  fn map_edges() {
+    assert_eq!(
+      neighbours(TilePos(0, 0), (3, 3)).collect::<HashSet<_>>(),
+      HashSet::from([TilePos(0, 1), TilePos(1, 0)])
+    );
+    assert_eq!(
+      neighbours(TilePos(2, 2), (3, 3)).collect::<HashSet<_>>(),
+      HashSet::from([TilePos(1, 2), TilePos(2, 1)])
+    );
+
+    // the only way around is along column 0 and then row 0
+    let walls = [(1, 1), (1, 2), (1, 3)];
+    let path = path_next_to(TilePos(0, 3), TilePos(3, 0), (4, 4), without(&walls)).unwrap();
+    assert_eq!(
+      positions(&path),
+      vec![(0, 2), (0, 1), (0, 0), (1, 0), (2, 0)]
+    );
+  }
+}
@@ -1,30 +1,856 @@
+use std::marker::PhantomData;
+use std::path::Path;
+
+use bevy::asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset};
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy_ecs_tilemap::TilePos;
+
+use crate::constants;
+use crate::map::{DataLayer, TileKind, TileTemp, Wall};
+use crate::map_entities::{
+  enemy::Enemy, player::PlayerStatus, EntityHealth, MapEntityType, PlayerType,
+};
+use crate::turn::{CastSpell, CompletedTurn, EndTurn, EnemyTurnAnimating, TurnLog};
+use crate::GameState;
 
+pub mod wizard_bytecode;
+pub mod wizard_conflicts;
+pub mod wizard_costs;
+#[cfg(test)]
+mod wizard_harness;
+pub mod wizard_interpreter;
 pub mod wizard_lang;
 pub mod wizard_memory;
+pub mod wizard_progression;
+pub mod wizard_serialize;
 pub mod wizard_types;
 
+use wizard_bytecode::Bytecode;
+use wizard_conflicts::{CastChanges, Conflict, ConflictPolicy};
+use wizard_costs::ManaCostTable;
+use wizard_interpreter::{
+  Interpreter, Program, SpellContext, SpellError, SpellOutput, SpellTrace, MAX_SPELL_STEPS,
+};
+use wizard_lang::ParseError;
+use wizard_memory::{MemoryBlob, MemoryCell, MemoryLocation, MemoryMap};
+use wizard_progression::{EnemyMemoryMap, Progression};
+use wizard_types::FormRegistry;
+use wizard_serialize::{FieldChange, MemoryLayout, TileRecord, UnitRecord, WorldSnapshot};
+
+type UnitQuery<'a> = (
+  Entity,
+  &'a mut TilePos,
+  &'a mut EntityHealth,
+  Option<&'a mut PlayerStatus>,
+  Option<&'a mut Enemy>,
+);
+
+type TileQuery<'a> = (
+  Entity,
+  &'a TilePos,
+  &'a DataLayer,
+  &'a mut TileTemp,
+  Option<&'a mut Wall>,
+);
+
+/// Anything that shows up in memory, or the start of the enemy turn, means memory is out of date
+type NewThings = Or<(
+  Added<EntityHealth>,
+  Added<DataLayer>,
+  Added<EnemyTurnAnimating>,
+)>;
+
+/// Where spell files are loaded from, relative to the asset folder
+const SPELL_FOLDER: &str = "spells";
+const SPELL_EXTENSION: &str = "spell";
+
+/// Settings for a spell, read from `# key: value` comments at the top of its source
+#[derive(Debug, Clone, Default)]
+pub struct SpellMeta {
+  pub desc: String,
+  /// The most magika the spell is allowed to spend on changes, on top of the casting cost
+  pub mana_budget: Option<f32>,
+  /// The most instructions the spell can run before it fizzles
+  pub step_limit: Option<usize>,
+  /// The forms the spell works with, which have to be unlocked before it can be cast
+  pub forms: Vec<String>,
+  /// What the spell should do to the test world, checked by `wizard_harness`
+  pub expect: Vec<String>,
+  /// Who casts the spell in the test world, like `Enemy 0`. The wizard casts it if there is none.
+  pub caster: Option<String>,
+  /// Decides whose write is kept when casts in the same turn clash, higher goes first
+  pub priority: i32,
+}
+
+impl SpellMeta {
+  fn from_source(source: &str) -> Self {
+    let mut meta = SpellMeta::default();
+    let header = source
+      .lines()
+      .map(str::trim)
+      .take_while(|line| line.starts_with('#'))
+      .filter_map(|line| line.trim_start_matches('#').split_once(':'));
+    for (key, value) in header {
+      let value = value.trim();
+      match key.trim() {
+        "desc" => meta.desc = value.to_owned(),
+        "mana" => meta.mana_budget = value.parse().ok(),
+        "steps" => meta.step_limit = value.parse().ok(),
+        "priority" => meta.priority = value.parse().unwrap_or(0),
+        "expect" => meta.expect.push(value.to_owned()),
+        "caster" => meta.caster = Some(value.to_owned()),
+        "forms" => {
+          meta.forms = value
+            .split(',')
+            .map(str::trim)
+            .filter(|f| !f.is_empty())
+            .map(str::to_owned)
+            .collect()
+        }
+        _ => {}
+      }
+    }
+    meta
+  }
+}
+
 #[derive(Component, Debug, Clone)]
 pub struct AvailableSpell {
   pub name: String,
   pub desc: String,
+  /// Written in the wizard language, see `wizard_lang`.
+  /// Private so that it can only change through `set_source`, which keeps `compiled` up to date.
+  source: String,
+  /// The compiled source, so it doesn't have to be parsed every time the spell is cast
+  compiled: Result<Bytecode, ParseError>,
+  pub meta: SpellMeta,
 }
 
-fn spawn_spells(mut commands: Commands) {
-  let spells = vec![
-    ("Test1", "The first test spell"),
-    ("Test2", "The second test spell"),
-  ];
-
-  spells
-    .into_iter()
-    .map(|(name, desc)| AvailableSpell {
+impl AvailableSpell {
+  pub fn new(name: &str, source: String) -> Self {
+    let meta = SpellMeta::from_source(&source);
+    Self {
       name: name.to_owned(),
-      desc: desc.to_owned(),
+      desc: meta.desc.clone(),
+      compiled: wizard_lang::parse(&source).map(|ast| Bytecode::compile(&ast)),
+      source,
+      meta,
+    }
+  }
+
+  pub fn source(&self) -> &str {
+    &self.source
+  }
+
+  pub fn compiled(&self) -> Result<&Bytecode, &ParseError> {
+    self.compiled.as_ref()
+  }
+
+  /// Replaces the source, and compiles it again
+  pub fn set_source(&mut self, source: String) {
+    *self = Self::new(&self.name, source);
+  }
+}
+
+/// What a spell did to memory, before any of it is written back to the game
+#[derive(Debug, Clone)]
+pub struct SpellRun {
+  pub result: Result<SpellOutput, SpellError>,
+  pub changes: Vec<FieldChange>,
+  /// The magika the changes cost, not counting the cost of casting
+  pub change_cost: f32,
+  /// Only recorded when `RunOptions::trace` is set
+  pub trace: Option<SpellTrace>,
+}
+
+impl SpellRun {
+  pub fn fizzled(&self) -> bool {
+    self.result.as_ref().err().is_some_and(|e| e.is_fizzle())
+  }
+
+  pub fn total_cost(&self) -> f32 {
+    constants::SPELL_MAGIKA_COST + self.change_cost
+  }
+
+  pub fn within_budget(&self, spell: &AvailableSpell) -> bool {
+    spell
+      .meta
+      .mana_budget
+      .is_none_or(|budget| self.change_cost <= budget)
+  }
+}
+
+/// The trace of the last spell that was cast or simulated, shown in the spell debugger
+#[derive(Default)]
+pub struct LatestTrace(pub Option<RecordedTrace>);
+
+pub struct RecordedTrace {
+  pub spell: String,
+  /// The source that was run, which might not be saved in the spell yet
+  pub source: String,
+  pub trace: SpellTrace,
+  /// The error that stopped the spell, if there was one
+  pub error: Option<SpellError>,
+}
+
+impl RecordedTrace {
+  pub fn new(spell: &str, source: &str, run: &SpellRun) -> Self {
+    Self {
+      spell: spell.to_owned(),
+      source: source.to_owned(),
+      trace: run.trace.clone().unwrap_or_default(),
+      error: run.result.as_ref().err().cloned(),
+    }
+  }
+}
+
+/// What a single cast printed
+#[derive(Debug, Clone)]
+pub struct ConsoleEntry {
+  /// Starts at 1
+  pub turn: usize,
+  pub caster: String,
+  pub spell: String,
+  pub lines: Vec<String>,
+  /// Why the spell failed, if it did
+  pub error: Option<String>,
+  /// How writes that clashed with other casts in the same turn were settled
+  pub conflicts: Vec<String>,
+}
+
+/// Everything spells have printed during the current level
+#[derive(Default)]
+pub struct SpellConsole {
+  pub entries: Vec<ConsoleEntry>,
+}
+
+/// The name a caster is shown with. Enemies are told apart by their entity id.
+fn caster_name(caster: Entity, kind: Option<&MapEntityType>) -> String {
+  match kind {
+    Some(MapEntityType::Player(PlayerType::Wizard)) => "Wizard".to_owned(),
+    Some(MapEntityType::Player(PlayerType::Warrior)) => "Warrior".to_owned(),
+    Some(MapEntityType::Enemy) => format!("Enemy {}", caster.id()),
+    _ => "Someone".to_owned(),
+  }
+}
+
+fn clear_console(mut console: ResMut<SpellConsole>, mut queued: ResMut<QueuedCasts>) {
+  console.entries.clear();
+  queued.0.clear();
+}
+
+/// How many instructions a spell can run before it fizzles.
+/// Casters with more magika can run longer spells, and spells can set a lower limit for themselves.
+/// No spell can run more than `MAX_SPELL_STEPS`.
+pub fn step_budget(spell: &AvailableSpell, magika: f32) -> usize {
+  let from_magika = (magika.max(0.) as usize) * constants::SPELL_STEPS_PER_MAGIKA;
+  spell
+    .meta
+    .step_limit
+    .map_or(from_magika, |limit| limit.min(from_magika))
+    .min(MAX_SPELL_STEPS)
+}
+
+/// How a spell is run
+#[derive(Debug, Clone, Copy)]
+pub struct RunOptions {
+  pub step_limit: usize,
+  pub caster: Option<Entity>,
+  /// Records a `SpellTrace` for the spell debugger, which slows the spell down
+  pub trace: bool,
+}
+
+/// Runs a spell against memory that already holds the game state, and works out what it changed.
+/// If the spell fizzles, none of its changes count.
+pub fn run_spell<P: Program>(
+  program: &P,
+  options: RunOptions,
+  memory: &mut MemoryBlob,
+  map: &MemoryMap,
+  layout: &MemoryLayout,
+  forms: &FormRegistry,
+  costs: &ManaCostTable,
+) -> SpellRun {
+  memory.reset_player_memory();
+  let mut trace = SpellTrace::default();
+  let mut interpreter = Interpreter::new(memory, map)
+    .with_step_limit(options.step_limit)
+    .with_context(SpellContext::new(layout, forms, options.caster));
+  if options.trace {
+    interpreter = interpreter.with_trace(&mut trace);
+  }
+  let result = interpreter.run(program);
+  let changes = match &result {
+    Err(e) if e.is_fizzle() => vec![],
+    _ => wizard_serialize::diff(layout, forms, memory),
+  };
+  SpellRun {
+    result,
+    change_cost: costs.cost_of(&changes),
+    changes,
+    trace: options.trace.then_some(trace),
+  }
+}
+
+/// Runs a spell, then puts memory back the way it was.
+/// Several dry runs can share one copy of memory, instead of copying it for each of them.
+pub fn dry_run<P: Program>(
+  program: &P,
+  options: RunOptions,
+  memory: &mut MemoryBlob,
+  map: &MemoryMap,
+  layout: &MemoryLayout,
+  forms: &FormRegistry,
+  costs: &ManaCostTable,
+) -> SpellRun {
+  memory.checkpoint();
+  let run = run_spell(program, options, memory, map, layout, forms, costs);
+  memory.rollback();
+  run
+}
+
+/// The text of a spell file, loaded by `SpellLoader`
+#[derive(Debug, TypeUuid)]
+#[uuid = "93fc6902-7b84-402b-a9bd-be84953133a5"]
+pub struct SpellSource(pub String);
+
+/// Loads `.spell` files from the asset folder
+#[derive(Default)]
+pub struct SpellLoader;
+
+impl AssetLoader for SpellLoader {
+  fn load<'a>(
+    &'a self,
+    bytes: &'a [u8],
+    load_context: &'a mut LoadContext,
+  ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+    Box::pin(async move {
+      let source = std::str::from_utf8(bytes)?.to_owned();
+      load_context.set_default_asset(LoadedAsset::new(SpellSource(source)));
+      Ok(())
     })
-    .for_each(|spell| {
+  }
+
+  fn extensions(&self) -> &[&str] {
+    &[SPELL_EXTENSION]
+  }
+}
+
+/// The spell files that are being loaded
+struct SpellHandles(Vec<HandleUntyped>);
+
+/// The file name is used as the name of the spell, with spaces instead of underscores
+fn spell_name(path: &Path) -> Option<String> {
+  path
+    .file_stem()?
+    .to_str()
+    .map(|name| name.replace('_', " "))
+}
+
+fn load_spells(mut commands: Commands, asset_server: Res<AssetServer>) {
+  let handles = asset_server.load_folder(SPELL_FOLDER).unwrap_or_else(|e| {
+    warn!("could not load spells from {}: {}", SPELL_FOLDER, e);
+    vec![]
+  });
+  commands.insert_resource(SpellHandles(handles));
+}
+
+/// Spawns an `AvailableSpell` for each spell file once it has loaded
+fn spawn_loaded_spells(
+  mut commands: Commands,
+  mut events: EventReader<AssetEvent<SpellSource>>,
+  sources: Res<Assets<SpellSource>>,
+  asset_server: Res<AssetServer>,
+  handles: Res<SpellHandles>,
+) {
+  for event in events.iter() {
+    let handle = match event {
+      AssetEvent::Created { handle } if handles.0.iter().any(|h| h.id == handle.id) => handle,
+      _ => continue,
+    };
+    let name = asset_server
+      .get_handle_path(handle)
+      .and_then(|path| spell_name(path.path()));
+    if let (Some(name), Some(source)) = (name, sources.get(handle)) {
+      let spell = AvailableSpell::new(&name, source.0.clone());
+      if let Err(e) = spell.compiled() {
+        warn!("spell {} has an error on {}", spell.name, e);
+      }
       commands.spawn().insert(spell);
+    }
+  }
+}
+
+/// Copies the parts of the game state that spells can see
+fn take_snapshot(
+  units: &Query<UnitQuery, Without<DataLayer>>,
+  tiles: &Query<TileQuery>,
+) -> WorldSnapshot {
+  let mut snapshot = WorldSnapshot::default();
+  for (entity, pos, health, status, enemy) in units.iter() {
+    let record = UnitRecord {
+      entity,
+      pos: pos.to_owned(),
+      health: health.health,
+      max_health: health.max,
+      magika: status.and_then(|s| s.magika).or_else(|| enemy.and_then(|e| e.magika)),
+      speed: enemy.map(|e| e.speed).unwrap_or(0),
+    };
+    if enemy.is_some() {
+      snapshot.enemies.push(record);
+    } else {
+      snapshot.players.push(record);
+    }
+  }
+  for (entity, pos, data, temp, wall) in tiles.iter() {
+    snapshot.tiles.push(TileRecord {
+      entity,
+      pos: pos.to_owned(),
+      temp: temp.temp,
+      wall_health: wall.map(|w| w.health),
+      walkable: data.kind == TileKind::Floor,
+    });
+  }
+  snapshot.sort();
+  snapshot
+}
+
+/// Copies a snapshot that changes were applied to back on to the real components.
+/// Components are only touched if their value changed.
+fn write_back(
+  after: &WorldSnapshot,
+  units: &mut Query<UnitQuery, Without<DataLayer>>,
+  tiles: &mut Query<TileQuery>,
+) {
+  for unit in after.players.iter().chain(after.enemies.iter()) {
+    if let Ok((_, mut pos, mut health, status, enemy)) = units.get_mut(unit.entity) {
+      if *pos != unit.pos {
+        *pos = unit.pos;
+      }
+      if health.health != unit.health {
+        health.health = unit.health;
+      }
+      if let Some(mut status) = status.filter(|s| s.magika.is_some() && s.magika != unit.magika) {
+        status.magika = unit.magika;
+      }
+      if let Some(mut enemy) = enemy.filter(|e| e.speed != unit.speed) {
+        enemy.speed = unit.speed;
+      }
+    }
+  }
+  for tile in after.tiles.iter() {
+    if let Ok((_, _, _, mut temp, wall)) = tiles.get_mut(tile.entity) {
+      if temp.temp != tile.temp {
+        temp.temp = tile.temp;
+      }
+      if let Some(mut wall) = wall {
+        if let Some(wall_health) = tile.wall_health.filter(|h| *h != wall.health) {
+          wall.health = wall_health;
+        }
+      }
+    }
+  }
+}
+
+/// Keeps the game memory up to date, so the player can look at it between casts.
+/// Also runs when the enemy turn starts, so enemy spellcasters see where the players moved to.
+pub fn refresh_memory_image(
+  mut end_turn: EventReader<EndTurn>,
+  mut memory: ResMut<MemoryBlob>,
+  mut layout: ResMut<MemoryLayout>,
+  forms: Res<FormRegistry>,
+  new_things: Query<Entity, NewThings>,
+  units: Query<UnitQuery, Without<DataLayer>>,
+  tiles: Query<TileQuery>,
+) {
+  if end_turn.iter().count() == 0 && new_things.is_empty() {
+    return;
+  }
+  let snapshot = take_snapshot(&units, &tiles);
+  let new_layout = wizard_serialize::serialize(&snapshot, &forms, &mut memory, &layout);
+  *layout = new_layout;
+}
+
+/// Sent by the memory viewer in debug mode, to change a single memory cell
+pub struct EditMemory {
+  pub address: usize,
+  pub value: u8,
+}
+
+/// Applies debug edits to memory, and propagates them on to the game like a free spell
+pub fn apply_memory_edits(
+  mut edits: EventReader<EditMemory>,
+  mut memory: ResMut<MemoryBlob>,
+  mut layout: ResMut<MemoryLayout>,
+  forms: Res<FormRegistry>,
+  mut units: Query<UnitQuery, Without<DataLayer>>,
+  mut tiles: Query<TileQuery>,
+) {
+  let edits: Vec<&EditMemory> = edits.iter().collect();
+  if edits.is_empty() {
+    return;
+  }
+
+  for edit in edits {
+    let location = MemoryLocation {
+      pointer: edit.address,
+    };
+    let cell = match memory.get_one(location) {
+      Some(cell) => match MemoryCell::try_new(cell.cell_type, edit.value) {
+        Ok(cell) => cell,
+        Err(e) => {
+          warn!("could not edit memory: {}", e);
+          continue;
+        }
+      },
+      None => continue,
+    };
+    if let Err(e) = memory.write_mem(std::iter::once(&cell), edit.address) {
+      warn!("could not edit memory: {}", e);
+    }
+  }
+
+  let snapshot = take_snapshot(&units, &tiles);
+  let changes = wizard_serialize::diff(&layout, &forms, &memory);
+  let after = wizard_serialize::apply_changes(&snapshot, &changes);
+  write_back(&after, &mut units, &mut tiles);
+
+  // show the game state as it is now, in case an edit was rejected
+  let snapshot = take_snapshot(&units, &tiles);
+  let new_layout = wizard_serialize::serialize(&snapshot, &forms, &mut memory, &layout);
+  *layout = new_layout;
+}
+
+/// What happens when a spell is cast on a snapshot of the world
+pub struct CastResult {
+  pub run: SpellRun,
+  /// Whether the spell's changes were kept
+  pub succeeded: bool,
+  /// How much magika the caster has to pay
+  pub spent: f32,
+}
+
+/// The memory a cast runs in, and what it needs to read and write it
+pub struct CastContext<'a> {
+  pub memory: &'a mut MemoryBlob,
+  pub layout: &'a mut MemoryLayout,
+  pub map: &'a MemoryMap,
+  pub forms: &'a FormRegistry,
+  pub costs: &'a ManaCostTable,
+}
+
+/// Writes the snapshot to memory and runs the spell on it, the same way for real casts and tests.
+/// Each changed cell costs magika; if the caster can't pay for all of them, the spell
+/// goes over its mana budget, or it stops with an error, none of the changes are kept and memory is put back.
+pub fn cast_spell(
+  spell: &AvailableSpell,
+  program: &Bytecode,
+  caster: Entity,
+  snapshot: &WorldSnapshot,
+  context: CastContext,
+) -> CastResult {
+  let CastContext {
+    memory,
+    layout,
+    map,
+    forms,
+    costs,
+  } = context;
+  *layout = wizard_serialize::serialize(snapshot, forms, memory, layout);
+
+  let magika = snapshot
+    .players
+    .iter()
+    .chain(snapshot.enemies.iter())
+    .find(|u| u.entity == caster)
+    .and_then(|p| p.magika)
+    .unwrap_or(0.);
+  let options = RunOptions {
+    step_limit: step_budget(spell, magika),
+    caster: Some(caster),
+    // real casts are shown in the spell debugger
+    trace: true,
+  };
+  let run = run_spell(program, options, memory, map, layout, forms, costs);
+  let succeeded = run.result.is_ok() && run.total_cost() <= magika && run.within_budget(spell);
+  if succeeded {
+    return CastResult {
+      spent: run.total_cost(),
+      succeeded,
+      run,
+    };
+  }
+
+  // put the game state back the way it was
+  *layout = wizard_serialize::serialize(snapshot, forms, memory, layout);
+  CastResult {
+    run,
+    succeeded,
+    // Even if the spell fails, the cost of casting has been spent
+    spent: constants::SPELL_MAGIKA_COST,
+  }
+}
+
+/// Describes how a conflict turned out for one of the casts in it
+fn conflict_line(conflict: &Conflict, cast: usize, names: &[String]) -> String {
+  let others: Vec<&str> = conflict
+    .casts
+    .iter()
+    .filter(|other| **other != cast)
+    .map(|other| names[*other].as_str())
+    .collect();
+  match conflict.winner {
+    Some(winner) if winner == cast => format!(
+      "its write to {} beat {}",
+      conflict.target(),
+      others.join(", ")
+    ),
+    Some(winner) => format!(
+      "its write to {} was overridden by {}",
+      conflict.target(),
+      names[winner]
+    ),
+    None => format!(
+      "its write to {} was cancelled out by {}",
+      conflict.target(),
+      others.join(", ")
+    ),
+  }
+}
+
+/// Describes a whole conflict for the turn log
+fn conflict_summary(conflict: &Conflict, names: &[String]) -> String {
+  let casts: Vec<&str> = conflict.casts.iter().map(|c| names[*c].as_str()).collect();
+  let outcome = match conflict.winner {
+    Some(winner) => format!("{} won", names[winner]),
+    None => "they cancelled out".to_owned(),
+  };
+  format!(
+    "{} all wrote to {}, {}",
+    casts.join(", "),
+    conflict.target(),
+    outcome
+  )
+}
+
+/// Spell memory, and everything needed to read and write it
+#[derive(SystemParam)]
+pub struct SpellMemory<'w, 's> {
+  memory: ResMut<'w, MemoryBlob>,
+  map: Res<'w, MemoryMap>,
+  enemy_map: Res<'w, EnemyMemoryMap>,
+  layout: ResMut<'w, MemoryLayout>,
+  forms: Res<'w, FormRegistry>,
+  costs: Res<'w, ManaCostTable>,
+  #[system_param(ignore)]
+  marker: PhantomData<&'s ()>,
+}
+
+/// Which spells can be cast, and how casts that clash are settled
+#[derive(SystemParam)]
+pub struct SpellRules<'w, 's> {
+  spells: Query<'w, 's, &'static AvailableSpell>,
+  progression: Res<'w, Progression>,
+  policy: Res<'w, ConflictPolicy>,
+}
+
+/// Casts from the current round, which wait until the enemies have acted too
+#[derive(Default)]
+pub struct QueuedCasts(Vec<CastSpell>);
+
+/// The casts made during a round, handed over once the round is over
+#[derive(SystemParam)]
+pub struct CastQueue<'w, 's> {
+  events: EventReader<'w, 's, CastSpell>,
+  queued: ResMut<'w, QueuedCasts>,
+  round_ended: RemovedComponents<'w, EnemyTurnAnimating>,
+}
+
+impl<'w, 's> CastQueue<'w, 's> {
+  /// Queues new casts, and returns every cast from the round if it has just ended
+  fn take_round(&mut self) -> Vec<CastSpell> {
+    self.queued.0.extend(self.events.iter().cloned());
+    if self.round_ended.iter().next().is_none() {
+      return vec![];
+    }
+    std::mem::take(&mut self.queued.0)
+  }
+}
+
+/// Where casts are reported
+#[derive(SystemParam)]
+pub struct SpellLog<'w, 's> {
+  latest_trace: ResMut<'w, LatestTrace>,
+  console: ResMut<'w, SpellConsole>,
+  turn_log: ResMut<'w, TurnLog>,
+  kinds: Query<'w, 's, &'static MapEntityType>,
+  completed_turns: Query<'w, 's, &'static CompletedTurn>,
+}
+
+/// Runs the spells that were cast during a round, once both the players and the enemies have acted.
+/// Has to run right after `turn::execute_turn`, so that it sees the enemy casts and the end of the round.
+/// Every cast runs on the same copy of the game state, then their changes are merged using the
+/// `ConflictPolicy` and written back together. Conflicts are reported in the spell console.
+pub fn resolve_casts(
+  mut queue: CastQueue,
+  spell_memory: SpellMemory,
+  rules: SpellRules,
+  log: SpellLog,
+  mut units: Query<UnitQuery, Without<DataLayer>>,
+  mut tiles: Query<TileQuery>,
+) {
+  let SpellMemory {
+    mut memory,
+    map,
+    enemy_map,
+    mut layout,
+    forms,
+    costs,
+    ..
+  } = spell_memory;
+  let SpellRules {
+    spells,
+    progression,
+    policy,
+  } = rules;
+  let SpellLog {
+    mut latest_trace,
+    mut console,
+    mut turn_log,
+    kinds,
+    completed_turns,
+  } = log;
+
+  let casts = queue.take_round();
+  if casts.is_empty() {
+    return;
+  }
+
+  let snapshot = take_snapshot(&units, &tiles);
+  // the caster, the spell, the caster's magika, and what happened
+  let mut resolved = vec![];
+  for CastSpell { caster, spell } in casts.iter() {
+    let spell = match spells.iter().find(|s| s.name == *spell) {
+      Some(s) => s,
+      None => {
+        warn!("can't cast unknown spell {}", spell);
+        continue;
+      }
+    };
+
+    // enemies know their spells without unlocking anything, and have their own permissions
+    let is_enemy = matches!(kinds.get(*caster), Ok(MapEntityType::Enemy));
+    let missing = progression.missing(&spell.meta.forms);
+    if !is_enemy && !missing.is_empty() {
+      info!("{} needs the locked forms {}", spell.name, missing.join(", "));
+      continue;
+    }
+
+    let magika = units
+      .get(*caster)
+      .ok()
+      .and_then(|(.., s, e)| s.and_then(|s| s.magika).or_else(|| e?.magika));
+    let magika = match magika {
+      Some(magika) if magika >= constants::SPELL_MAGIKA_COST => magika,
+      _ => {
+        info!("not enough magika to cast {}", spell.name);
+        continue;
+      }
+    };
+
+    let program = match spell.compiled() {
+      Ok(program) => program,
+      Err(e) => {
+        warn!("{} failed to parse: {}", spell.name, e);
+        continue;
+      }
+    };
+
+    let context = CastContext {
+      memory: &mut memory,
+      layout: &mut layout,
+      map: if is_enemy { &enemy_map.0 } else { &map },
+      forms: &forms,
+      costs: &costs,
+    };
+    let cast = cast_spell(spell, program, *caster, &snapshot, context);
+    latest_trace.0 = Some(RecordedTrace::new(&spell.name, spell.source(), &cast.run));
+    resolved.push((*caster, spell, magika, cast));
+  }
+
+  let cast_changes: Vec<CastChanges> = resolved
+    .iter()
+    .map(|(_, spell, _, cast)| CastChanges {
+      priority: spell.meta.priority,
+      changes: if cast.succeeded {
+        cast.run.changes.clone()
+      } else {
+        vec![]
+      },
+    })
+    .collect();
+  let (changes, conflicts) = wizard_conflicts::resolve_conflicts(&cast_changes, *policy);
+  let after = wizard_serialize::apply_changes(&snapshot, &changes);
+  write_back(&after, &mut units, &mut tiles);
+
+  let names: Vec<String> = resolved
+    .iter()
+    .map(|(caster, spell, ..)| {
+      format!("{}'s {}", caster_name(*caster, kinds.get(*caster).ok()), spell.name)
+    })
+    .collect();
+  let turn = completed_turns.iter().count() + 1;
+  for conflict in conflicts.iter() {
+    turn_log.entries.push((turn, conflict_summary(conflict, &names)));
+  }
+  for (index, (caster, spell, magika, cast)) in resolved.iter().enumerate() {
+    let error = match &cast.run.result {
+      Err(e) => Some(e.to_string()),
+      Ok(_) if cast.succeeded => None,
+      Ok(_) if cast.run.within_budget(spell) => Some(format!(
+        "needed {} magika, but only {} was available",
+        cast.run.total_cost(),
+        magika
+      )),
+      Ok(_) => Some("went over its mana budget".to_owned()),
+    };
+    if let Some(error) = &error {
+      info!("{} failed: {}", spell.name, error);
+    }
+    console.entries.push(ConsoleEntry {
+      turn,
+      caster: caster_name(*caster, kinds.get(*caster).ok()),
+      spell: spell.name.clone(),
+      lines: cast
+        .run
+        .result
+        .as_ref()
+        .map(|output| output.printed.clone())
+        .unwrap_or_default(),
+      error,
+      conflicts: conflicts
+        .iter()
+        .filter(|c| c.casts.contains(&index))
+        .map(|c| conflict_line(c, index, &names))
+        .collect(),
     });
+
+    // This overrides any changes the spell made to the caster's own magika.
+    // Casters pay for their changes even if another cast overrode them.
+    let left = Some((magika - cast.spent).max(0.));
+    match units.get_mut(*caster) {
+      Ok((.., Some(mut status), _)) => status.magika = left,
+      Ok((.., Some(mut enemy))) => enemy.magika = left,
+      _ => {}
+    }
+  }
+
+  // memory holds what the last cast did, so show the merged game state instead
+  let snapshot = take_snapshot(&units, &tiles);
+  let new_layout = wizard_serialize::serialize(&snapshot, &forms, &mut memory, &layout);
+  *layout = new_layout;
 }
 
 pub struct SpellsPlugin;
@@ -32,7 +858,196 @@ pub struct SpellsPlugin;
 impl Plugin for SpellsPlugin {
   fn build(&self, app: &mut App) {
     app
-      .add_startup_system(spawn_spells)
-      .insert_resource(wizard_memory::MemoryBlob::new());
+      .add_asset::<SpellSource>()
+      .init_asset_loader::<SpellLoader>()
+      .add_startup_system(load_spells)
+      .add_system(spawn_loaded_spells)
+      .insert_resource(wizard_memory::MemoryBlob::new())
+      .insert_resource(MemoryMap::default())
+      .insert_resource(EnemyMemoryMap::default())
+      .insert_resource(MemoryLayout::default())
+      .insert_resource(FormRegistry::default())
+      .insert_resource(ManaCostTable::default())
+      .insert_resource(Progression::default())
+      .insert_resource(ConflictPolicy::default())
+      .insert_resource(LatestTrace::default())
+      .insert_resource(SpellConsole::default())
+      .insert_resource(QueuedCasts::default())
+      .add_event::<EditMemory>()
+      .add_system_set(
+        SystemSet::on_update(GameState::Running)
+          .with_system(refresh_memory_image.label("refresh-memory-image"))
+          .with_system(wizard_progression::apply_progression)
+          .with_system(apply_memory_edits),
+      )
+      .add_system_set(SystemSet::on_exit(GameState::Running).with_system(clear_console))
+      .add_system_to_stage(CoreStage::PostUpdate, resolve_casts.after("execute-turn"));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use bevy::app::Events;
+  use wizard_interpreter::SpellErrorKind;
+
+  #[test]
+  fn infinite_loops_fizzle() {
+    let spell = AvailableSpell::new("loop", "set @0 1\nloop:\njump loop".to_owned());
+    let mut memory = MemoryBlob::new();
+    let run = run_spell(
+      spell.compiled().unwrap(),
+      RunOptions {
+        step_limit: 50,
+        caster: None,
+        trace: false,
+      },
+      &mut memory,
+      &MemoryMap::default(),
+      &MemoryLayout::default(),
+      &FormRegistry::default(),
+      &ManaCostTable::default(),
+    );
+    assert_eq!(
+      run.result.as_ref().unwrap_err().kind,
+      SpellErrorKind::TooManySteps(50)
+    );
+    assert!(run.fizzled());
+    assert!(run.changes.is_empty());
+  }
+
+  #[test]
+  fn errors_keep_none_of_the_changes() {
+    let world = wizard_harness::snapshot_of(&mut wizard_harness::test_world());
+    let wizard = world.players[0].entity;
+    // hurts the first enemy, then overflows
+    let spell = AvailableSpell::new("oops", "sub @2000:3 30\nset @0:2 90\nadd @0:2 20".to_owned());
+    let forms = FormRegistry::default();
+    let mut memory = MemoryBlob::new();
+    let mut layout = MemoryLayout::default();
+    let context = CastContext {
+      memory: &mut memory,
+      layout: &mut layout,
+      map: &MemoryMap::default(),
+      forms: &forms,
+      costs: &ManaCostTable::default(),
+    };
+    let cast = cast_spell(&spell, spell.compiled().unwrap(), wizard, &world, context);
+    assert_eq!(
+      cast.run.result.as_ref().unwrap_err().kind,
+      SpellErrorKind::Overflow(110)
+    );
+    assert!(!cast.succeeded);
+    assert_eq!(cast.spent, constants::SPELL_MAGIKA_COST);
+    assert!(wizard_serialize::diff(&layout, &forms, &memory).is_empty());
+  }
+
+  #[test]
+  fn casts_from_both_sides_resolve_together() {
+    let mut world = wizard_harness::test_world();
+    let snapshot = wizard_harness::snapshot_of(&mut world);
+    let (wizard, enemy) = (snapshot.players[0].entity, snapshot.enemies[0].entity);
+    world.entity_mut(wizard).insert(MapEntityType::Player(PlayerType::Wizard));
+    world.entity_mut(enemy).insert(MapEntityType::Enemy);
+    world.spawn().insert(AvailableSpell::new("mend", "add @1000:3 10".to_owned()));
+    world.spawn().insert(AvailableSpell::new("wound", "sub @1000:3 10".to_owned()));
+    world.insert_resource(MemoryBlob::new());
+    world.insert_resource(MemoryMap::default());
+    world.insert_resource(EnemyMemoryMap::default());
+    world.insert_resource(MemoryLayout::default());
+    world.insert_resource(FormRegistry::default());
+    world.insert_resource(ManaCostTable::default());
+    world.insert_resource(Progression::default());
+    world.insert_resource(ConflictPolicy::Priority);
+    world.insert_resource(LatestTrace::default());
+    world.insert_resource(SpellConsole::default());
+    world.insert_resource(TurnLog::default());
+    world.insert_resource(QueuedCasts::default());
+    world.insert_resource(Events::<CastSpell>::default());
+    let mut stage = SystemStage::single_threaded().with_system(resolve_casts);
+    let health = |world: &World| world.get::<EntityHealth>(wizard).unwrap().health;
+
+    // the player turn ends, but the wizard's cast waits for the enemies
+    world.get_resource_mut::<Events<CastSpell>>().unwrap().send(CastSpell {
+      caster: wizard,
+      spell: "mend".to_owned(),
+    });
+    stage.run(&mut world);
+    assert_eq!(health(&world), 80.);
+
+    // the enemy turn ends, and both casts write to the wizard's health
+    world.clear_trackers();
+    world.get_resource_mut::<Events<CastSpell>>().unwrap().send(CastSpell {
+      caster: enemy,
+      spell: "wound".to_owned(),
+    });
+    let displayer = world.spawn().insert(EnemyTurnAnimating).id();
+    world.despawn(displayer);
+    stage.run(&mut world);
+
+    // priorities are tied, so the cast that resolved first wins
+    assert_eq!(health(&world), 90.);
+    let console = world.get_resource::<SpellConsole>().unwrap();
+    assert_eq!(console.entries.len(), 2);
+    assert!(console.entries.iter().all(|entry| entry.conflicts.len() == 1));
+    let turn_log = world.get_resource::<TurnLog>().unwrap();
+    assert_eq!(turn_log.entries.len(), 1);
+    assert!(turn_log.entries[0].1.ends_with("Wizard's mend won"));
+    assert!(world.get_resource::<QueuedCasts>().unwrap().0.is_empty());
+  }
+
+  #[test]
+  fn spell_files_load_through_the_asset_server() {
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(bevy::asset::AssetPlugin)
+      .add_asset::<SpellSource>()
+      .init_asset_loader::<SpellLoader>()
+      .add_startup_system(load_spells)
+      .add_system(spawn_loaded_spells);
+
+    let expected: Vec<String> = wizard_harness::read_spell_files()
+      .into_iter()
+      .map(|s| s.name)
+      .collect();
+    let mut names = vec![];
+    for _ in 0..100 {
+      app.update();
+      let world = &mut app.world;
+      names = world
+        .query::<&AvailableSpell>()
+        .iter(world)
+        .map(|s| s.name.clone())
+        .collect();
+      if names.len() >= expected.len() {
+        break;
+      }
+      std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    names.sort();
+    assert_eq!(names, expected);
+  }
+
+  #[test]
+  fn step_budget_takes_the_lowest_limit() {
+    let no_header = AvailableSpell::new("a", "print 1".to_owned());
+    let header = AvailableSpell::new("b", "# steps: 30\nprint 1".to_owned());
+    let huge_header = AvailableSpell::new("c", "# steps: 99999999\nprint 1".to_owned());
+
+    assert_eq!(step_budget(&no_header, 2.), 2 * constants::SPELL_STEPS_PER_MAGIKA);
+    assert_eq!(step_budget(&header, 100.), 30);
+    assert_eq!(step_budget(&header, 0.), 0);
+    assert_eq!(step_budget(&no_header, 1_000_000.), MAX_SPELL_STEPS);
+    assert_eq!(step_budget(&huge_header, 1_000_000.), MAX_SPELL_STEPS);
+  }
+
+  #[test]
+  fn enemies_are_named_by_entity() {
+    let enemy = Some(&MapEntityType::Enemy);
+    let first = caster_name(Entity::from_raw(7), enemy);
+    let second = caster_name(Entity::from_raw(8), enemy);
+    assert_eq!(first, "Enemy 7");
+    assert_ne!(first, second);
   }
 }
@@ -0,0 +1,419 @@
+//! Spells are compiled to bytecode when they are saved, so they don't have to be parsed every cast.
+//!
+//! Every instruction is stored as a list of words: the opcode, the source line it came from,
+//! then its arguments. Values take two words (a tag holding the kind of pointer and scalar type,
+//! then the address), plus a third for the offset of dynamic pointers. Literals are a tag then the number.
+//! Jumps hold the position of the instruction they go to.
+
+use super::wizard_interpreter::{Fetched, Program};
+use super::wizard_lang::{Function, Line, Operand, Pointer, Value, AST};
+use super::wizard_memory::MemoryLocation;
+use super::wizard_serialize::RecordKind;
+use super::wizard_types::WizardScalarType;
+
+const OP_PRINT: u32 = 0;
+const OP_SET: u32 = 1;
+const OP_ADD: u32 = 2;
+const OP_SUB: u32 = 3;
+const OP_MUL: u32 = 4;
+const OP_JUMP: u32 = 5;
+const OP_JUMPIF: u32 = 6;
+const OP_FIND: u32 = 7;
+const OP_TILE: u32 = 8;
+const OP_MYPOS: u32 = 9;
+const OP_DIST: u32 = 10;
+const OP_RANDOM: u32 = 11;
+
+const TAG_LITERAL: u32 = 0;
+const TAG_STATIC: u32 = 1;
+const TAG_DYNAMIC: u32 = 2;
+
+/// Scalar types are stored in the low byte of a tag. Integers use their digit count.
+const TYPE_CHAR: u32 = 10;
+const TYPE_BOOL: u32 = 11;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bytecode {
+  code: Vec<u32>,
+}
+
+fn encode_type(scalar_type: WizardScalarType) -> u32 {
+  match scalar_type {
+    WizardScalarType::Integer(digits) => digits,
+    WizardScalarType::Char => TYPE_CHAR,
+    WizardScalarType::Bool => TYPE_BOOL,
+  }
+}
+
+fn decode_type(code: u32) -> Option<WizardScalarType> {
+  match code {
+    TYPE_CHAR => Some(WizardScalarType::Char),
+    TYPE_BOOL => Some(WizardScalarType::Bool),
+    digits if digits > 0 && digits < TYPE_CHAR => Some(WizardScalarType::Integer(digits)),
+    _ => None,
+  }
+}
+
+fn encode_kind(kind: RecordKind) -> u32 {
+  match kind {
+    RecordKind::Player => 0,
+    RecordKind::Enemy => 1,
+    RecordKind::Tile => 2,
+  }
+}
+
+fn decode_kind(code: u32) -> Option<RecordKind> {
+  match code {
+    0 => Some(RecordKind::Player),
+    1 => Some(RecordKind::Enemy),
+    2 => Some(RecordKind::Tile),
+    _ => None,
+  }
+}
+
+/// Builds up bytecode. Jump targets are written as line numbers, then fixed up once every line has a position.
+struct Encoder {
+  code: Vec<u32>,
+  /// Indexes into `code` that hold a line number that needs to become a position
+  jumps: Vec<usize>,
+}
+
+impl Encoder {
+  fn value(&mut self, value: &Value) {
+    let scalar_type = encode_type(value.scalar_type);
+    match value.pointer {
+      Pointer::Static(location) => {
+        self.code.push((TAG_STATIC << 8) | scalar_type);
+        self.code.push(location.pointer as u32);
+      }
+      Pointer::Dynamic(location, offset) => {
+        self.code.push((TAG_DYNAMIC << 8) | scalar_type);
+        self.code.push(location.pointer as u32);
+        self.code.push(offset as u32);
+      }
+    }
+  }
+
+  fn operand(&mut self, operand: &Operand) {
+    match operand {
+      Operand::Literal(number) => {
+        self.code.push(TAG_LITERAL << 8);
+        self.code.push(*number);
+      }
+      Operand::Value(value) => self.value(value),
+    }
+  }
+
+  fn jump(&mut self, line: usize) {
+    self.jumps.push(self.code.len());
+    self.code.push(line as u32);
+  }
+
+  fn function(&mut self, function: &Function, line: usize) {
+    let opcode = match function {
+      Function::Print(_) => OP_PRINT,
+      Function::Set(..) => OP_SET,
+      Function::Add(..) => OP_ADD,
+      Function::Sub(..) => OP_SUB,
+      Function::Mul(..) => OP_MUL,
+      Function::Jump(_) => OP_JUMP,
+      Function::JumpIf(..) => OP_JUMPIF,
+      Function::Find(..) => OP_FIND,
+      Function::TileAt(..) => OP_TILE,
+      Function::MyPos(..) => OP_MYPOS,
+      Function::Distance(..) => OP_DIST,
+      Function::Random(..) => OP_RANDOM,
+    };
+    self.code.push(opcode);
+    self.code.push(line as u32);
+
+    match function {
+      Function::Print(src) => self.operand(src),
+      Function::Set(dest, src)
+      | Function::Add(dest, src)
+      | Function::Sub(dest, src)
+      | Function::Mul(dest, src)
+      | Function::Random(dest, src) => {
+        self.value(dest);
+        self.operand(src);
+      }
+      Function::Jump(target) => self.jump(*target),
+      Function::JumpIf(condition, target) => {
+        self.operand(condition);
+        self.jump(*target);
+      }
+      Function::Find(dest, kind, n) => {
+        self.value(dest);
+        self.code.push(encode_kind(*kind));
+        self.operand(n);
+      }
+      Function::TileAt(dest, x, y) => {
+        self.value(dest);
+        self.operand(x);
+        self.operand(y);
+      }
+      Function::MyPos(dest_x, dest_y) => {
+        self.value(dest_x);
+        self.value(dest_y);
+      }
+      Function::Distance(dest, positions) => {
+        self.value(dest);
+        for operand in positions.iter() {
+          self.operand(operand);
+        }
+      }
+    }
+  }
+}
+
+/// Reads instructions back out of bytecode
+struct Decoder<'a> {
+  code: &'a [u32],
+  position: usize,
+}
+
+impl<'a> Decoder<'a> {
+  fn word(&mut self) -> Option<u32> {
+    let word = self.code.get(self.position).copied();
+    self.position += 1;
+    word
+  }
+
+  fn value_with_tag(&mut self, tag: u32) -> Option<Value> {
+    let scalar_type = decode_type(tag & 0xff)?;
+    let location = MemoryLocation {
+      pointer: self.word()? as usize,
+    };
+    let pointer = match tag >> 8 {
+      TAG_STATIC => Pointer::Static(location),
+      TAG_DYNAMIC => Pointer::Dynamic(location, self.word()? as usize),
+      _ => return None,
+    };
+    Some(Value {
+      pointer,
+      scalar_type,
+    })
+  }
+
+  fn value(&mut self) -> Option<Value> {
+    let tag = self.word()?;
+    self.value_with_tag(tag)
+  }
+
+  fn operand(&mut self) -> Option<Operand> {
+    let tag = self.word()?;
+    if tag >> 8 == TAG_LITERAL {
+      return Some(Operand::Literal(self.word()?));
+    }
+    Some(Operand::Value(self.value_with_tag(tag)?))
+  }
+
+  fn jump(&mut self) -> Option<usize> {
+    self.word().map(|target| target as usize)
+  }
+
+  fn function(&mut self) -> Option<(Function, usize)> {
+    let opcode = self.word()?;
+    let line = self.word()? as usize;
+    let function = match opcode {
+      OP_PRINT => Function::Print(self.operand()?),
+      OP_SET => Function::Set(self.value()?, self.operand()?),
+      OP_ADD => Function::Add(self.value()?, self.operand()?),
+      OP_SUB => Function::Sub(self.value()?, self.operand()?),
+      OP_MUL => Function::Mul(self.value()?, self.operand()?),
+      OP_JUMP => Function::Jump(self.jump()?),
+      OP_JUMPIF => Function::JumpIf(self.operand()?, self.jump()?),
+      OP_FIND => Function::Find(self.value()?, decode_kind(self.word()?)?, self.operand()?),
+      OP_TILE => Function::TileAt(self.value()?, self.operand()?, self.operand()?),
+      OP_MYPOS => Function::MyPos(self.value()?, self.value()?),
+      OP_DIST => Function::Distance(
+        self.value()?,
+        [
+          self.operand()?,
+          self.operand()?,
+          self.operand()?,
+          self.operand()?,
+        ],
+      ),
+      OP_RANDOM => Function::Random(self.value()?, self.operand()?),
+      _ => return None,
+    };
+    Some((function, line))
+  }
+}
+
+impl Bytecode {
+  pub fn compile(ast: &AST) -> Self {
+    let mut encoder = Encoder {
+      code: vec![],
+      jumps: vec![],
+    };
+    // where the code for each line starts, lines without instructions start where the next one does
+    let mut line_positions = Vec::with_capacity(ast.lines.len());
+    for (line, text) in ast.lines.iter().enumerate() {
+      line_positions.push(encoder.code.len());
+      if let Line::Instruction(function) = text {
+        encoder.function(function, line);
+      }
+    }
+
+    for index in encoder.jumps {
+      let line = encoder.code[index] as usize;
+      encoder.code[index] = line_positions
+        .get(line)
+        .copied()
+        .unwrap_or(encoder.code.len()) as u32;
+    }
+    Self { code: encoder.code }
+  }
+
+  /// How many words of code there are
+  pub fn word_count(&self) -> usize {
+    self.code.len()
+  }
+
+  /// Lists every instruction with its position and source line, for debugging
+  pub fn disassemble(&self) -> String {
+    let mut text = String::new();
+    let mut position = 0;
+    while let Some(fetched) = self.fetch(position) {
+      text.push_str(&format!(
+        "{:04}  line {:<3} {}\n",
+        position,
+        fetched.line + 1,
+        disassemble_function(&fetched.function)
+      ));
+      position = fetched.next;
+    }
+    text
+  }
+}
+
+fn disassemble_function(function: &Function) -> String {
+  match function {
+    Function::Print(src) => format!("print {}", src),
+    Function::Set(dest, src) => format!("set {} {}", dest, src),
+    Function::Add(dest, src) => format!("add {} {}", dest, src),
+    Function::Sub(dest, src) => format!("sub {} {}", dest, src),
+    Function::Mul(dest, src) => format!("mul {} {}", dest, src),
+    Function::Jump(target) => format!("jump {:04}", target),
+    Function::JumpIf(condition, target) => format!("jumpif {} {:04}", condition, target),
+    Function::Find(dest, kind, n) => format!("find {} {} {}", dest, kind.form_name(), n),
+    Function::TileAt(dest, x, y) => format!("tile {} {} {}", dest, x, y),
+    Function::MyPos(dest_x, dest_y) => format!("mypos {} {}", dest_x, dest_y),
+    Function::Distance(dest, [x1, y1, x2, y2]) => {
+      format!("dist {} {} {} {} {}", dest, x1, y1, x2, y2)
+    }
+    Function::Random(dest, max) => format!("random {} {}", dest, max),
+  }
+}
+
+impl Program for Bytecode {
+  fn fetch(&self, position: usize) -> Option<Fetched> {
+    let mut decoder = Decoder {
+      code: &self.code,
+      position,
+    };
+    let (function, line) = decoder.function()?;
+    Some(Fetched {
+      function,
+      line,
+      next: decoder.position,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::spells::wizard_interpreter::{Interpreter, SpellOutput};
+  use crate::spells::wizard_lang::parse;
+  use crate::spells::wizard_memory::{MemoryBlob, MemoryMap};
+
+  /// Jumps forwards past a label, loops backwards, and has lines without instructions
+  const LOOPS: &str = "\
+# counts @0:2 down from 5, adding to @2:2 each time
+set @0:2 5
+jump start
+
+skipped:
+set @2:2 99
+start:
+add @2:2 @0:2
+sub @0:2 1
+jumpif @0:2 start
+jumpif @0:2 skipped
+print @2:2
+";
+
+  /// Runs `program` on empty memory, and returns what it printed and the first few memory cells
+  fn run<P: Program>(program: &P) -> (Vec<String>, Vec<u8>) {
+    let mut memory = MemoryBlob::new();
+    let map = MemoryMap::default();
+    let SpellOutput { printed, .. } = Interpreter::new(&mut memory, &map).run(program).unwrap();
+    let cells = memory.get_many(0, 8).unwrap();
+    (printed, cells.iter().map(|cell| cell.value).collect())
+  }
+
+  #[test]
+  fn every_instruction_survives_compiling() {
+    let source = "\
+set @0:2 5
+print *0+3:c
+find @10:6 Enemy @0:2
+tile @16:6 1 2
+mypos @22:3 @25:3
+dist @28:3 @22:3 @25:3 1 *0:b
+random @31:3 10
+mul @31:3 2
+jumpif @31:3 end
+sub @31:3 1
+end:
+";
+    let ast = parse(source).unwrap();
+    let bytecode = Bytecode::compile(&ast);
+    let mut position = 0;
+    let mut count = 0;
+    while let Some(compiled) = bytecode.fetch(position) {
+      let parsed = ast.fetch(compiled.line).unwrap();
+      assert_eq!(parsed.line, compiled.line);
+      match (&parsed.function, &compiled.function) {
+        (Function::JumpIf(a, line), Function::JumpIf(b, target)) => {
+          assert_eq!(a, b);
+          // both should go to the same source line
+          assert_eq!(
+            ast.fetch(*line).map(|f| f.line),
+            bytecode.fetch(*target).map(|f| f.line)
+          );
+        }
+        (parsed, compiled) => assert_eq!(parsed, compiled),
+      }
+      position = compiled.next;
+      count += 1;
+    }
+    assert_eq!(count, 10);
+    assert_eq!(position, bytecode.word_count());
+  }
+
+  #[test]
+  fn disassembly_lists_positions_and_lines() {
+    let ast = parse("set @0:2 5\nloop:\nsub @0:2 1\njumpif @0:2 loop\nprint @0:2").unwrap();
+    assert_eq!(
+      Bytecode::compile(&ast).disassemble(),
+      "\
+0000  line 1   set @0:2 5
+0006  line 3   sub @0:2 1
+0012  line 4   jumpif @0:2 0006
+0017  line 5   print @0:2
+"
+    );
+  }
+
+  #[test]
+  fn bytecode_runs_the_same_as_the_parsed_spell() {
+    let ast = parse(LOOPS).unwrap();
+    let parsed = run(&ast);
+    assert_eq!(parsed, run(&Bytecode::compile(&ast)));
+    assert_eq!(parsed.0, vec!["15".to_owned()]);
+  }
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+
+use super::wizard_serialize::FieldChange;
+
+/// How to pick which write is kept when casts in the same turn change the same memory
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ConflictPolicy {
+  /// The spell with the highest priority wins. Ties go to the cast that resolved first.
+  #[default]
+  Priority,
+  /// The cast that resolved last wins
+  LastWriter,
+  /// None of the conflicting writes happen, so spells can counter each other
+  Cancel,
+}
+
+impl ConflictPolicy {
+  /// Explains the policy to the player
+  pub fn describe(&self) -> &'static str {
+    match self {
+      ConflictPolicy::Priority => "the spell with the highest priority wins",
+      ConflictPolicy::LastWriter => "the last spell to resolve wins",
+      ConflictPolicy::Cancel => "clashing spells cancel each other out",
+    }
+  }
+}
+
+/// The changes that one successful cast wants to make, in the order the casts resolved
+pub struct CastChanges {
+  pub priority: i32,
+  pub changes: Vec<FieldChange>,
+}
+
+/// Several casts wrote different values to the same field
+#[derive(Debug, Clone)]
+pub struct Conflict {
+  /// The first of the conflicting changes, used to describe what was written to
+  pub change: FieldChange,
+  /// Indexes of every cast that wrote to the field
+  pub casts: Vec<usize>,
+  /// The cast whose write was kept, `None` if they were all cancelled
+  pub winner: Option<usize>,
+}
+
+impl Conflict {
+  /// Describes the field that was fought over, like `@2000 Enemy 5 health[0]`
+  pub fn target(&self) -> String {
+    format!(
+      "@{} {} {} {}[{}]",
+      self.change.address,
+      self.change.kind.form_name(),
+      self.change.entity.id(),
+      self.change.field,
+      self.change.element
+    )
+  }
+}
+
+fn pick_winner(writes: &[(usize, i32, &FieldChange)], policy: ConflictPolicy) -> Option<usize> {
+  match policy {
+    // `max_by_key` returns the last of equal elements, so go through them backwards
+    ConflictPolicy::Priority => writes
+      .iter()
+      .rev()
+      .max_by_key(|(_, priority, _)| *priority)
+      .map(|(cast, ..)| *cast),
+    ConflictPolicy::LastWriter => writes.last().map(|(cast, ..)| *cast),
+    ConflictPolicy::Cancel => None,
+  }
+}
+
+/// Merges the changes from casts that happened at the same time into one list.
+/// Writes of the same value to a field aren't conflicts; otherwise `policy` decides which one is kept.
+pub fn resolve_conflicts(
+  casts: &[CastChanges],
+  policy: ConflictPolicy,
+) -> (Vec<FieldChange>, Vec<Conflict>) {
+  // sorted by address, so that the result is the same every time
+  let mut by_address: BTreeMap<usize, Vec<(usize, i32, &FieldChange)>> = BTreeMap::new();
+  for (index, cast) in casts.iter().enumerate() {
+    for change in cast.changes.iter() {
+      by_address
+        .entry(change.address)
+        .or_default()
+        .push((index, cast.priority, change));
+    }
+  }
+
+  let mut merged = vec![];
+  let mut conflicts = vec![];
+  for writes in by_address.values() {
+    let first = writes[0].2;
+    if writes.iter().all(|(_, _, change)| change.new == first.new) {
+      merged.push(first.clone());
+      continue;
+    }
+
+    let winner = pick_winner(writes, policy);
+    if let Some((.., change)) = writes.iter().find(|(cast, ..)| Some(*cast) == winner) {
+      merged.push((*change).clone());
+    }
+    conflicts.push(Conflict {
+      change: first.clone(),
+      casts: writes.iter().map(|(cast, ..)| *cast).collect(),
+      winner,
+    });
+  }
+  (merged, conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::spells::wizard_serialize::RecordKind;
+  use bevy::prelude::Entity;
+
+  /// A cast that writes `new` to the health of the enemy at `address`
+  fn cast(priority: i32, writes: &[(usize, u32)]) -> CastChanges {
+    CastChanges {
+      priority,
+      changes: writes
+        .iter()
+        .map(|&(address, new)| FieldChange {
+          entity: Entity::from_raw(address as u32),
+          kind: RecordKind::Enemy,
+          field: "health".to_owned(),
+          element: 0,
+          address,
+          old: 50,
+          new,
+          changed_cells: 1,
+        })
+        .collect(),
+    }
+  }
+
+  /// The value written to each address, in address order
+  fn written(changes: &[FieldChange]) -> Vec<(usize, u32)> {
+    changes.iter().map(|c| (c.address, c.new)).collect()
+  }
+
+  #[test]
+  fn priority_keeps_the_highest_priority_write() {
+    let casts = [cast(0, &[(2000, 10)]), cast(5, &[(2000, 20)]), cast(1, &[(2000, 30)])];
+    let (changes, conflicts) = resolve_conflicts(&casts, ConflictPolicy::Priority);
+    assert_eq!(written(&changes), vec![(2000, 20)]);
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0].casts, vec![0, 1, 2]);
+    assert_eq!(conflicts[0].winner, Some(1));
+  }
+
+  #[test]
+  fn priority_ties_go_to_the_first_cast() {
+    let casts = [cast(3, &[(2000, 10)]), cast(3, &[(2000, 20)]), cast(3, &[(2000, 30)])];
+    let (changes, conflicts) = resolve_conflicts(&casts, ConflictPolicy::Priority);
+    assert_eq!(written(&changes), vec![(2000, 10)]);
+    assert_eq!(conflicts[0].winner, Some(0));
+  }
+
+  #[test]
+  fn last_writer_keeps_the_last_cast() {
+    let casts = [cast(9, &[(2000, 10)]), cast(0, &[(2000, 20)])];
+    let (changes, conflicts) = resolve_conflicts(&casts, ConflictPolicy::LastWriter);
+    assert_eq!(written(&changes), vec![(2000, 20)]);
+    assert_eq!(conflicts[0].winner, Some(1));
+  }
+
+  #[test]
+  fn cancel_drops_every_conflicting_write() {
+    let casts = [cast(0, &[(2000, 10), (2006, 40)]), cast(0, &[(2000, 20)])];
+    let (changes, conflicts) = resolve_conflicts(&casts, ConflictPolicy::Cancel);
+    // the write nobody else touched still happens
+    assert_eq!(written(&changes), vec![(2006, 40)]);
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0].winner, None);
+  }
+
+  #[test]
+  fn same_writes_are_not_conflicts_and_results_are_sorted() {
+    let casts = [cast(0, &[(2012, 5), (2000, 10)]), cast(0, &[(2000, 10), (2006, 7)])];
+    for policy in [ConflictPolicy::Priority, ConflictPolicy::LastWriter, ConflictPolicy::Cancel] {
+      let (changes, conflicts) = resolve_conflicts(&casts, policy);
+      assert_eq!(written(&changes), vec![(2000, 10), (2006, 7), (2012, 5)]);
+      assert!(conflicts.is_empty());
+    }
+  }
+}
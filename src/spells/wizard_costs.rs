@@ -0,0 +1,50 @@
+use bevy::utils::HashMap;
+
+use super::wizard_serialize::FieldChange;
+
+/// Fields that aren't in the table cost this much per cell
+const DEFAULT_CELL_COST: f32 = 1.;
+
+/// How much magika it takes to change a single memory cell of a field.
+/// Keyed by form name, then field name.
+pub struct ManaCostTable {
+  costs: HashMap<(String, String), f32>,
+}
+
+impl ManaCostTable {
+  pub fn cell_cost(&self, form: &str, field: &str) -> f32 {
+    self
+      .costs
+      .get(&(form.to_owned(), field.to_owned()))
+      .copied()
+      .unwrap_or(DEFAULT_CELL_COST)
+  }
+
+  /// The magika it takes to make all of the changes, not counting the cost of casting
+  pub fn cost_of(&self, changes: &[FieldChange]) -> f32 {
+    changes
+      .iter()
+      .map(|c| self.cell_cost(c.kind.form_name(), &c.field) * c.changed_cells as f32)
+      .sum()
+  }
+}
+
+impl Default for ManaCostTable {
+  fn default() -> Self {
+    let costs = [
+      ("Player", "health", 2.),
+      ("Player", "position", 5.),
+      ("Player", "magika", 10.),
+      ("Enemy", "health", 4.),
+      ("Enemy", "position", 8.),
+      ("Enemy", "speed", 5.),
+      ("Tile", "temperature", 0.5),
+      ("Tile", "wall_health", 2.),
+    ]
+    .iter()
+    .map(|(form, field, cost)| ((form.to_string(), field.to_string()), *cost))
+    .collect();
+
+    Self { costs }
+  }
+}
@@ -0,0 +1,306 @@
+//! Runs spells against a small test world without starting the game, and checks what they did.
+//! `cargo test` tests every spell in the spell folder.
+//!
+//! Expectations are written in the spell's header, one per line:
+//! `# expect: Enemy 0 health 70` means that after the wizard casts the spell, the first enemy
+//! record should have 70 health. Records are numbered in memory order, and positions are written as `x,y`.
+//! Spells written for enemies can be cast by another record with `# caster: Enemy 0`.
+//! Spells are cast and written back to the test world the same way as in the game, so expectations are
+//! checked against the components of the test world.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+use bevy_ecs_tilemap::TilePos;
+
+use super::wizard_costs::ManaCostTable;
+use super::wizard_memory::{MemoryBlob, MemoryMap};
+use super::wizard_progression::EnemyMemoryMap;
+use super::wizard_serialize::{apply_changes, MemoryLayout, UnitRecord, WorldSnapshot};
+use super::wizard_types::FormRegistry;
+use super::{
+  cast_spell, spell_name, take_snapshot, write_back, AvailableSpell, CastContext, TileQuery,
+  UnitQuery, SPELL_EXTENSION, SPELL_FOLDER,
+};
+use crate::map::{DataLayer, TileKind, TileTemp, Wall};
+use crate::map_entities::{enemy::Enemy, player::PlayerStatus, EntityHealth};
+
+/// The test world is a square of floor tiles this wide, with a wall in the far corner
+const TEST_WORLD_SIZE: u32 = 5;
+/// Values are compared with this much leeway, since they are stored as floats
+const TOLERANCE: f32 = 0.01;
+
+/// A small world with a wizard, a warrior and one enemy, who knows the enemy spells
+pub fn test_world() -> World {
+  let mut world = World::new();
+  let enemy_spells = read_spell_files()
+    .into_iter()
+    .filter(|spell| spell.meta.caster.is_some())
+    .map(|spell| spell.name)
+    .collect();
+  let health = |health| EntityHealth {
+    health,
+    max: 100.,
+  };
+  world.spawn().insert_bundle((
+    TilePos(0, 0),
+    health(80.),
+    PlayerStatus {
+      magika: Some(100.),
+    },
+  ));
+  world
+    .spawn()
+    .insert_bundle((TilePos(1, 0), health(100.), PlayerStatus::default()));
+  world
+    .spawn()
+    .insert_bundle((TilePos(3, 3), health(100.), Enemy::caster(60., enemy_spells)));
+  for y in 0..TEST_WORLD_SIZE {
+    for x in 0..TEST_WORLD_SIZE {
+      let is_wall = x == TEST_WORLD_SIZE - 1 && y == TEST_WORLD_SIZE - 1;
+      let kind = if is_wall {
+        TileKind::Wall
+      } else {
+        TileKind::Floor
+      };
+      let mut tile = world.spawn();
+      tile.insert_bundle((TilePos(x, y), DataLayer { kind }, TileTemp { temp: 20. }));
+      if is_wall {
+        tile.insert(Wall { health: 50. });
+      }
+    }
+  }
+  world
+}
+
+fn store_snapshot(
+  mut commands: Commands,
+  units: Query<UnitQuery, Without<DataLayer>>,
+  tiles: Query<TileQuery>,
+) {
+  commands.insert_resource(take_snapshot(&units, &tiles));
+}
+
+/// What the spell systems see of `world`
+pub fn snapshot_of(world: &mut World) -> WorldSnapshot {
+  SystemStage::single_threaded()
+    .with_system(store_snapshot)
+    .run(world);
+  world
+    .remove_resource::<WorldSnapshot>()
+    .expect("the snapshot should have been stored")
+}
+
+/// Reads every spell in the spell folder straight from disk, since there is no `AssetServer` here
+pub fn read_spell_files() -> Vec<AvailableSpell> {
+  let folder = Path::new(env!("CARGO_MANIFEST_DIR"))
+    .join("assets")
+    .join(SPELL_FOLDER);
+  let mut paths: Vec<PathBuf> = fs::read_dir(&folder)
+    .expect("the spell folder should exist")
+    .filter_map(|entry| entry.ok().map(|e| e.path()))
+    .filter(|path| path.extension().is_some_and(|ext| ext == SPELL_EXTENSION))
+    .collect();
+  paths.sort();
+  paths
+    .iter()
+    .filter_map(|path| {
+      let source = fs::read_to_string(path).ok()?;
+      Some(AvailableSpell::new(&spell_name(path)?, source))
+    })
+    .collect()
+}
+
+/// The value of a field in game units, read from the components of the entity stored in that record,
+/// or `None` if the record doesn't have it
+fn field_value(
+  world: &World,
+  records: &WorldSnapshot,
+  form: &str,
+  index: usize,
+  field: &str,
+) -> Option<Vec<f32>> {
+  let entity = match form {
+    "Player" => records.players.get(index)?.entity,
+    "Enemy" => records.enemies.get(index)?.entity,
+    "Tile" => records.tiles.get(index)?.entity,
+    _ => return None,
+  };
+  match field {
+    "position" => world.get::<TilePos>(entity).map(|p| vec![p.0 as f32, p.1 as f32]),
+    "health" => world.get::<EntityHealth>(entity).map(|h| vec![h.health]),
+    "magika" => world
+      .get::<PlayerStatus>(entity)
+      .and_then(|s| s.magika)
+      .or_else(|| world.get::<Enemy>(entity)?.magika)
+      .map(|m| vec![m]),
+    "speed" => world.get::<Enemy>(entity).map(|e| vec![e.speed as f32]),
+    "temperature" => world.get::<TileTemp>(entity).map(|t| vec![t.temp]),
+    "wall_health" => world.get::<Wall>(entity).map(|w| vec![w.health]),
+    _ => None,
+  }
+}
+
+/// Checks a single `Form index field value` expectation
+fn check_expectation(world: &World, records: &WorldSnapshot, expect: &str) -> Result<(), String> {
+  let parts: Vec<&str> = expect.split_whitespace().collect();
+  let (form, index, field, value) = match parts.as_slice() {
+    [form, index, field, value] => (*form, *index, *field, *value),
+    _ => return Err(format!("can't understand expectation \"{}\"", expect)),
+  };
+  let index: usize = index
+    .parse()
+    .map_err(|_| format!("{} is not a record number", index))?;
+  let expected = value
+    .split(',')
+    .map(|v| v.trim().parse::<f32>())
+    .collect::<Result<Vec<f32>, _>>()
+    .map_err(|_| format!("{} is not a number", value))?;
+
+  let actual = field_value(world, records, form, index, field)
+    .ok_or_else(|| format!("{} {} has no field {}", form, index, field))?;
+  let matches = actual.len() == expected.len()
+    && actual
+      .iter()
+      .zip(expected.iter())
+      .all(|(a, e)| (a - e).abs() < TOLERANCE);
+  if matches {
+    Ok(())
+  } else {
+    Err(format!(
+      "expected {} {} {} to be {:?}, but it was {:?}",
+      form, index, field, expected, actual
+    ))
+  }
+}
+
+pub struct SpellTestResult {
+  pub spell: String,
+  /// Empty if the spell passed
+  pub failures: Vec<String>,
+}
+
+/// The spell `cast_test_spell` casts, and what happened
+struct TestCast {
+  spell: AvailableSpell,
+  /// The world before the cast, which gives the order of the records
+  before: Option<WorldSnapshot>,
+  failures: Vec<String>,
+}
+
+/// Finds the record a `Form index` caster refers to
+fn find_caster<'a>(snapshot: &'a WorldSnapshot, caster: &str) -> Option<&'a UnitRecord> {
+  let (form, index) = caster.split_once(' ')?;
+  let index: usize = index.trim().parse().ok()?;
+  match form {
+    "Player" => snapshot.players.get(index),
+    "Enemy" => snapshot.enemies.get(index),
+    _ => None,
+  }
+}
+
+/// Has the spell's caster cast it, and writes the changes back the same way `resolve_casts` does
+fn cast_test_spell(
+  mut test: ResMut<TestCast>,
+  mut units: Query<UnitQuery, Without<DataLayer>>,
+  mut tiles: Query<TileQuery>,
+) {
+  let test = &mut *test;
+  let program = match test.spell.compiled() {
+    Ok(program) => program,
+    Err(e) => {
+      test.failures.push(format!("parse error on {}", e));
+      return;
+    }
+  };
+  let snapshot = take_snapshot(&units, &tiles);
+  let name = test.spell.meta.caster.as_deref().unwrap_or("Player 0");
+  let caster = match find_caster(&snapshot, name) {
+    Some(caster) => caster,
+    None => {
+      test.failures.push(format!("the test world has no caster {}", name));
+      return;
+    }
+  };
+  let map = if snapshot.enemies.iter().any(|e| e.entity == caster.entity) {
+    EnemyMemoryMap::default().0
+  } else {
+    MemoryMap::default()
+  };
+  let magika = caster.magika.unwrap_or(0.);
+  let mut memory = MemoryBlob::new();
+  let mut layout = MemoryLayout::default();
+  let context = CastContext {
+    memory: &mut memory,
+    layout: &mut layout,
+    map: &map,
+    forms: &FormRegistry::default(),
+    costs: &ManaCostTable::default(),
+  };
+  let cast = cast_spell(&test.spell, program, caster.entity, &snapshot, context);
+  if let Err(e) = &cast.run.result {
+    test.failures.push(e.to_string());
+  }
+
+  if cast.succeeded {
+    let after = apply_changes(&snapshot, &cast.run.changes);
+    write_back(&after, &mut units, &mut tiles);
+  }
+  let left = Some((magika - cast.spent).max(0.));
+  match units.get_mut(caster.entity) {
+    Ok((.., Some(mut status), _)) => status.magika = left,
+    Ok((.., Some(mut enemy))) => enemy.magika = left,
+    _ => {}
+  }
+  test.before = Some(snapshot);
+}
+
+/// Has the spell's caster cast it on `world`, then checks the spell's expectations
+pub fn test_spell(spell: &AvailableSpell, world: &mut World) -> SpellTestResult {
+  world.insert_resource(TestCast {
+    spell: spell.clone(),
+    before: None,
+    failures: vec![],
+  });
+  SystemStage::single_threaded()
+    .with_system(cast_test_spell)
+    .run(world);
+  let test = world
+    .remove_resource::<TestCast>()
+    .expect("the test cast should still be there");
+
+  let mut result = SpellTestResult {
+    spell: spell.name.clone(),
+    failures: test.failures,
+  };
+  if let Some(before) = test.before {
+    for expect in spell.meta.expect.iter() {
+      if let Err(failure) = check_expectation(world, &before, expect) {
+        result.failures.push(failure);
+      }
+    }
+  }
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn spells_in_the_spell_folder_do_what_they_expect() {
+    let spells = read_spell_files();
+    assert!(!spells.is_empty());
+    for spell in spells.iter() {
+      assert!(!spell.meta.expect.is_empty(), "{} has no expectations", spell.name);
+      let result = test_spell(spell, &mut test_world());
+      assert!(
+        result.failures.is_empty(),
+        "{} failed: {}",
+        result.spell,
+        result.failures.join(", ")
+      );
+    }
+  }
+}
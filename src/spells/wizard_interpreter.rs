@@ -0,0 +1,521 @@
+use bevy::prelude::Entity;
+use bevy_ecs_tilemap::TilePos;
+
+use super::wizard_lang::{Function, Line, Operand, Pointer, Value, AST};
+use super::wizard_memory::{
+  cells_to_number, Access, MemoryBlob, MemoryCell, MemoryError, MemoryMap, ADDRESS_WIDTH,
+};
+use super::wizard_serialize::{MemoryLayout, RecordKind};
+use super::wizard_types::{FormRegistry, WizardScalarType};
+
+/// Stops spells that loop forever from freezing the game, no matter what their step limit is
+pub const MAX_SPELL_STEPS: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpellErrorKind {
+  /// Reading or writing memory failed
+  Memory(MemoryError),
+  /// The number does not fit in the space it is being written to
+  Overflow(u32),
+  /// The spell ran out of steps before it finished, so it fizzles
+  TooManySteps(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpellError {
+  /// the line that was running when the error happened, starts at 0
+  pub line: usize,
+  pub kind: SpellErrorKind,
+}
+
+impl std::fmt::Display for SpellError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match &self.kind {
+      SpellErrorKind::Memory(e) => write!(f, "line {}: {}", self.line + 1, e),
+      SpellErrorKind::Overflow(value) => {
+        write!(f, "line {}: {} does not fit in memory", self.line + 1, value)
+      }
+      SpellErrorKind::TooManySteps(limit) => {
+        write!(f, "the spell fizzled after running {} steps", limit)
+      }
+    }
+  }
+}
+
+impl SpellError {
+  /// A spell that fizzles doesn't change anything
+  pub fn is_fizzle(&self) -> bool {
+    matches!(self.kind, SpellErrorKind::TooManySteps(_))
+  }
+}
+
+/// What a spell did when it ran, other than change memory
+#[derive(Debug, Clone, Default)]
+pub struct SpellOutput {
+  pub printed: Vec<String>,
+  pub steps: usize,
+}
+
+/// Something a spell did to memory
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+  Read {
+    address: usize,
+    count: usize,
+    value: u32,
+  },
+  Write {
+    address: usize,
+    count: usize,
+    old: u32,
+    new: u32,
+  },
+  /// A `*` pointer read the address stored at `from`
+  Dereference { from: usize, to: usize },
+}
+
+impl TraceEvent {
+  /// The memory cells that the event touched, as `(start, count)`
+  pub fn cells(&self) -> (usize, usize) {
+    match self {
+      TraceEvent::Read { address, count, .. } => (*address, *count),
+      TraceEvent::Write { address, count, .. } => (*address, *count),
+      TraceEvent::Dereference { from, .. } => (*from, ADDRESS_WIDTH),
+    }
+  }
+}
+
+impl std::fmt::Display for TraceEvent {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      TraceEvent::Read {
+        address, value, ..
+      } => write!(f, "read {} from @{}", value, address),
+      TraceEvent::Write {
+        address, old, new, ..
+      } => write!(f, "wrote {} to @{} (was {})", new, address, old),
+      TraceEvent::Dereference { from, to } => write!(f, "followed *{} to @{}", from, to),
+    }
+  }
+}
+
+/// Everything that happened while running a single instruction
+#[derive(Debug, Clone)]
+pub struct TraceStep {
+  pub line: usize,
+  pub events: Vec<TraceEvent>,
+}
+
+/// A record of every instruction a spell ran, for debugging
+#[derive(Debug, Clone, Default)]
+pub struct SpellTrace {
+  pub steps: Vec<TraceStep>,
+}
+
+/// A record in memory, as seen by built-in functions
+#[derive(Debug, Clone)]
+pub struct ContextRecord {
+  pub kind: RecordKind,
+  pub start: usize,
+  pub pos: Option<TilePos>,
+}
+
+/// What built-in functions know about the world, as it was when the spell was cast
+#[derive(Debug, Clone, Default)]
+pub struct SpellContext {
+  pub caster_pos: Option<TilePos>,
+  /// In the same order as they are in memory
+  pub records: Vec<ContextRecord>,
+  /// Seeds `random`, so that a spell cast in the same situation does the same thing
+  pub seed: u64,
+}
+
+impl SpellContext {
+  pub fn new(layout: &MemoryLayout, forms: &FormRegistry, caster: Option<Entity>) -> Self {
+    let records: Vec<ContextRecord> = layout
+      .records
+      .iter()
+      .map(|r| ContextRecord {
+        kind: r.kind,
+        start: r.start,
+        pos: r.position(forms),
+      })
+      .collect();
+    let caster_pos = caster
+      .and_then(|c| layout.record_of(c))
+      .and_then(|r| r.position(forms));
+
+    // mix together everything that was written to memory
+    let mut seed = caster.map_or(0, |c| c.id() as u64);
+    for value in layout.records.iter().flat_map(|r| r.original.iter()) {
+      seed = seed.wrapping_mul(31).wrapping_add(*value as u64);
+    }
+
+    Self {
+      caster_pos,
+      records,
+      seed,
+    }
+  }
+
+  fn find(&self, kind: RecordKind, n: usize) -> Option<&ContextRecord> {
+    self.records.iter().filter(|r| r.kind == kind).nth(n)
+  }
+
+  fn tile_at(&self, pos: TilePos) -> Option<&ContextRecord> {
+    self
+      .records
+      .iter()
+      .find(|r| r.kind == RecordKind::Tile && r.pos == Some(pos))
+  }
+}
+
+/// One instruction of a program, ready to run
+pub struct Fetched {
+  pub function: Function,
+  /// The source line the instruction came from
+  pub line: usize,
+  /// Where the instruction after this one starts
+  pub next: usize,
+}
+
+/// Something the interpreter can run: a parsed spell, or its compiled bytecode.
+/// Jump targets are positions in the program.
+pub trait Program {
+  /// Gets the instruction at or after `position`, or `None` once the program is done
+  fn fetch(&self, position: usize) -> Option<Fetched>;
+}
+
+impl Program for AST {
+  fn fetch(&self, position: usize) -> Option<Fetched> {
+    self
+      .lines
+      .iter()
+      .enumerate()
+      .skip(position)
+      .find_map(|(line, text)| match text {
+        Line::Instruction(function) => Some(Fetched {
+          function: function.clone(),
+          line,
+          next: line + 1,
+        }),
+        _ => None,
+      })
+  }
+}
+
+/// Runs a parsed spell against the game memory.
+/// Memory can only be used in the ways that `map` allows.
+pub struct Interpreter<'a> {
+  memory: &'a mut MemoryBlob,
+  map: &'a MemoryMap,
+  line: usize,
+  step_limit: usize,
+  output: SpellOutput,
+  trace: Option<&'a mut SpellTrace>,
+  context: SpellContext,
+  /// State of the random number generator, never 0
+  random_state: u64,
+}
+
+impl<'a> Interpreter<'a> {
+  pub fn new(memory: &'a mut MemoryBlob, map: &'a MemoryMap) -> Self {
+    Self {
+      memory,
+      map,
+      line: 0,
+      step_limit: MAX_SPELL_STEPS,
+      output: SpellOutput::default(),
+      trace: None,
+      context: SpellContext::default(),
+      random_state: 1,
+    }
+  }
+
+  pub fn with_context(mut self, context: SpellContext) -> Self {
+    // xorshift gets stuck at 0
+    self.random_state = context.seed.max(1);
+    self.context = context;
+    self
+  }
+
+  /// A xorshift generator, so that spells are repeatable
+  fn next_random(&mut self) -> u64 {
+    let mut x = self.random_state;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    self.random_state = x;
+    x
+  }
+
+  /// Records what the spell does into `trace`, which is kept even if the spell fails
+  pub fn with_trace(mut self, trace: &'a mut SpellTrace) -> Self {
+    self.trace = Some(trace);
+    self
+  }
+
+  fn record(&mut self, event: TraceEvent) {
+    if let Some(step) = self.trace.as_mut().and_then(|t| t.steps.last_mut()) {
+      step.events.push(event);
+    }
+  }
+
+  /// Makes the spell fizzle after `limit` instructions
+  pub fn with_step_limit(mut self, limit: usize) -> Self {
+    self.step_limit = limit.min(MAX_SPELL_STEPS);
+    self
+  }
+
+  fn error(&self, kind: SpellErrorKind) -> SpellError {
+    SpellError {
+      line: self.line,
+      kind,
+    }
+  }
+
+  fn check(&self, address: usize, count: usize, access: Access) -> Result<(), SpellError> {
+    self
+      .map
+      .check(address, count, access)
+      .map_err(|e| self.error(SpellErrorKind::Memory(e)))
+  }
+
+  /// Reads a number without checking permissions or recording it
+  fn load(&self, address: usize, count: usize) -> Result<u32, SpellError> {
+    self
+      .memory
+      .get_many(address, count)
+      .map(cells_to_number)
+      .map_err(|e| self.error(SpellErrorKind::Memory(e)))
+  }
+
+  fn read_digits(&mut self, address: usize, count: usize) -> Result<u32, SpellError> {
+    self.check(address, count, Access::Read)?;
+    let value = self.load(address, count)?;
+    self.record(TraceEvent::Read {
+      address,
+      count,
+      value,
+    });
+    Ok(value)
+  }
+
+  fn write_digits(&mut self, address: usize, count: usize, number: u32) -> Result<(), SpellError> {
+    if number as u64 >= 10u64.pow(count as u32) {
+      return Err(self.error(SpellErrorKind::Overflow(number)));
+    }
+    self.check(address, count, Access::Write)?;
+    // keep the type hints that are already in memory
+    let mut cells = self
+      .memory
+      .get_many(address, count)
+      .map_err(|e| self.error(SpellErrorKind::Memory(e)))?
+      .to_vec();
+    let old = cells_to_number(&cells);
+    let mut remaining = number;
+    for cell in cells.iter_mut().rev() {
+      *cell = MemoryCell::new(cell.cell_type, (remaining % 10) as u8);
+      remaining /= 10;
+    }
+    self
+      .memory
+      .write_mem(cells.iter(), address)
+      .map_err(|e| self.error(SpellErrorKind::Memory(e)))?;
+    self.record(TraceEvent::Write {
+      address,
+      count,
+      old,
+      new: number,
+    });
+    Ok(())
+  }
+
+  fn address(&mut self, pointer: &Pointer) -> Result<usize, SpellError> {
+    match pointer {
+      Pointer::Static(location) => Ok(location.pointer),
+      Pointer::Dynamic(location, offset) => {
+        self.check(location.pointer, ADDRESS_WIDTH, Access::Execute)?;
+        self.check(location.pointer, ADDRESS_WIDTH, Access::Read)?;
+        // recorded as a dereference instead of a read
+        let to = self.load(location.pointer, ADDRESS_WIDTH)? as usize + offset;
+        self.record(TraceEvent::Dereference {
+          from: location.pointer,
+          to,
+        });
+        Ok(to)
+      }
+    }
+  }
+
+  fn read_value(&mut self, value: &Value) -> Result<u32, SpellError> {
+    let address = self.address(&value.pointer)?;
+    let number = self.read_digits(address, value.scalar_type.size())?;
+    match value.scalar_type {
+      WizardScalarType::Bool => Ok((number != 0) as u32),
+      _ => Ok(number),
+    }
+  }
+
+  fn write_value(&mut self, value: &Value, number: u32) -> Result<(), SpellError> {
+    let address = self.address(&value.pointer)?;
+    let number = match value.scalar_type {
+      WizardScalarType::Bool => (number != 0) as u32,
+      _ => number,
+    };
+    self.write_digits(address, value.scalar_type.size(), number)
+  }
+
+  fn read_operand(&mut self, operand: &Operand) -> Result<u32, SpellError> {
+    match operand {
+      Operand::Literal(number) => Ok(*number),
+      Operand::Value(value) => self.read_value(value),
+    }
+  }
+
+  fn display_operand(&mut self, operand: &Operand) -> Result<String, SpellError> {
+    let number = self.read_operand(operand)?;
+    Ok(match operand {
+      Operand::Value(Value {
+        scalar_type: WizardScalarType::Char,
+        ..
+      }) => char::from_u32(number).unwrap_or('?').to_string(),
+      Operand::Value(Value {
+        scalar_type: WizardScalarType::Bool,
+        ..
+      }) => (number != 0).to_string(),
+      _ => number.to_string(),
+    })
+  }
+
+  fn arithmetic(
+    &mut self,
+    dest: &Value,
+    src: &Operand,
+    op: fn(u32, u32) -> Option<u32>,
+  ) -> Result<(), SpellError> {
+    let a = self.read_value(dest)?;
+    let b = self.read_operand(src)?;
+    let result = op(a, b).ok_or_else(|| self.error(SpellErrorKind::Overflow(a)))?;
+    self.write_value(dest, result)
+  }
+
+  /// Runs a single instruction, and returns where to jump to if it jumps
+  fn execute(&mut self, function: &Function) -> Result<Option<usize>, SpellError> {
+    match function {
+      Function::Print(operand) => {
+        let text = self.display_operand(operand)?;
+        self.output.printed.push(text);
+      }
+      Function::Set(dest, src) => {
+        let number = self.read_operand(src)?;
+        self.write_value(dest, number)?;
+      }
+      Function::Add(dest, src) => self.arithmetic(dest, src, u32::checked_add)?,
+      // memory can't hold negative numbers, so subtraction stops at 0
+      Function::Sub(dest, src) => self.arithmetic(dest, src, |a, b| Some(a.saturating_sub(b)))?,
+      Function::Mul(dest, src) => self.arithmetic(dest, src, u32::checked_mul)?,
+      Function::Jump(target) => return Ok(Some(*target)),
+      Function::JumpIf(condition, target) => {
+        if self.read_operand(condition)? != 0 {
+          return Ok(Some(*target));
+        }
+      }
+      Function::Find(dest, kind, n) => {
+        let n = self.read_operand(n)? as usize;
+        let address = self.context.find(*kind, n).map_or(0, |r| r.start);
+        self.write_value(dest, address as u32)?;
+      }
+      Function::TileAt(dest, x, y) => {
+        let pos = TilePos(self.read_operand(x)?, self.read_operand(y)?);
+        let address = self.context.tile_at(pos).map_or(0, |r| r.start);
+        self.write_value(dest, address as u32)?;
+      }
+      Function::MyPos(dest_x, dest_y) => {
+        let pos = self.context.caster_pos.unwrap_or(TilePos(0, 0));
+        self.write_value(dest_x, pos.0)?;
+        self.write_value(dest_y, pos.1)?;
+      }
+      Function::Distance(dest, [x1, y1, x2, y2]) => {
+        let (x1, y1) = (self.read_operand(x1)? as i64, self.read_operand(y1)? as i64);
+        let (x2, y2) = (self.read_operand(x2)? as i64, self.read_operand(y2)? as i64);
+        let distance = (x1 - x2).abs() + (y1 - y2).abs();
+        self.write_value(dest, distance as u32)?;
+      }
+      Function::Random(dest, max) => {
+        let max = self.read_operand(max)? as u64;
+        let number = if max == 0 { 0 } else { self.next_random() % max };
+        self.write_value(dest, number as u32)?;
+      }
+    }
+    Ok(None)
+  }
+
+  /// Runs the whole spell. Memory that was written before an error is not undone.
+  pub fn run<P: Program>(mut self, program: &P) -> Result<SpellOutput, SpellError> {
+    let mut position = 0;
+    while let Some(fetched) = program.fetch(position) {
+      self.line = fetched.line;
+      self.output.steps += 1;
+      if self.output.steps > self.step_limit {
+        return Err(self.error(SpellErrorKind::TooManySteps(self.step_limit)));
+      }
+      if let Some(trace) = self.trace.as_mut() {
+        trace.steps.push(TraceStep {
+          line: fetched.line,
+          events: vec![],
+        });
+      }
+      position = self.execute(&fetched.function)?.unwrap_or(fetched.next);
+    }
+    Ok(self.output)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::spells::wizard_lang::parse;
+  use crate::spells::wizard_memory::MEMORY_SIZE;
+
+  fn run(source: &str) -> Result<SpellOutput, SpellError> {
+    let mut memory = MemoryBlob::new();
+    let map = MemoryMap::default();
+    Interpreter::new(&mut memory, &map).run(&parse(source).unwrap())
+  }
+
+  #[test]
+  fn arithmetic_overflow() {
+    let error = run("set @0:2 90\nadd @0:2 20").unwrap_err();
+    assert_eq!(error.line, 1);
+    assert_eq!(error.kind, SpellErrorKind::Overflow(110));
+
+    let error = run("set @0:9 999999999\nmul @0:9 999999999").unwrap_err();
+    assert_eq!(error.kind, SpellErrorKind::Overflow(999999999));
+  }
+
+  #[test]
+  fn infinite_loops_stop_at_the_step_limit() {
+    let mut memory = MemoryBlob::new();
+    let map = MemoryMap::default();
+    let error = Interpreter::new(&mut memory, &map)
+      .with_step_limit(25)
+      .run(&parse("loop:\njump loop").unwrap())
+      .unwrap_err();
+    assert_eq!(error.kind, SpellErrorKind::TooManySteps(25));
+    assert!(error.is_fizzle());
+
+    // without a limit, spells still stop eventually
+    let error = run("loop:\njump loop").unwrap_err();
+    assert_eq!(error.kind, SpellErrorKind::TooManySteps(MAX_SPELL_STEPS));
+  }
+
+  #[test]
+  fn addresses_past_the_end_of_memory() {
+    let error = run(&format!("set @{} 1", MEMORY_SIZE)).unwrap_err();
+    assert_eq!(
+      error.kind,
+      SpellErrorKind::Memory(MemoryError::OutOfBounds(MEMORY_SIZE))
+    );
+  }
+}
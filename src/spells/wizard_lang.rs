@@ -1,25 +1,420 @@
+//! The wizard language: a tiny assembly-like language that spells are written in.
+//!
+//! Every line holds at most one instruction. Everything after a `#` is a comment.
+//!
+//! Operands:
+//! - `42` a literal number
+//! - `@120` the single digit stored at address 120
+//! - `@120:3` the 3 digit integer stored at addresses 120 to 122
+//! - `@120:b` a bool, `@120:c` a character
+//! - `*120` follows the address stored in the 6 cells starting at 120
+//! - `*120+4:3` a 3 digit integer, 4 cells after the address stored at 120
+//!
+//! Instructions:
+//! - `set DEST SRC`, `add DEST SRC`, `sub DEST SRC`, `mul DEST SRC`
+//! - `print SRC`
+//! - `jump LABEL`, `jumpif SRC LABEL` (jumps when SRC is not 0)
+//! - `LABEL:` marks a place to jump to
+//!
+//! Built-in functions, which write their result to DEST.
+//! Addresses are 6 digits long, so they need a destination like `@0:6` to fit.
+//! - `find DEST FORM N` the address of the Nth record of a form (`Player`, `Enemy` or `Tile`),
+//!   counting from 0. Writes 0 if there isn't one.
+//! - `tile DEST X Y` the address of the tile at a position, or 0 if there isn't one
+//! - `mypos DEST_X DEST_Y` the position of the unit casting the spell
+//! - `dist DEST X1 Y1 X2 Y2` how many steps it takes to walk between two positions
+//! - `random DEST MAX` a random number from 0 up to (but not including) MAX
+//!
+//! Built-ins see the world as it was when the spell was cast.
+use bevy::utils::HashMap;
+
 use super::wizard_memory::MemoryLocation;
+use super::wizard_serialize::RecordKind;
 use super::wizard_types::WizardScalarType;
 
-enum Pointer {
+/// Integers larger than this can't be stored in memory
+pub const MAX_INTEGER_DIGITS: u32 = 9;
+
+/// The names of every instruction
+pub const INSTRUCTIONS: &[&str] = &[
+  "print", "set", "add", "sub", "mul", "jump", "jumpif", "find", "tile", "mypos", "dist", "random",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pointer {
   Static(MemoryLocation),
-  Dynamic(MemoryLocation),
+  /// The location holds an address, which is followed and then offset
+  Dynamic(MemoryLocation, usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Value {
+  pub pointer: Pointer,
+  pub scalar_type: WizardScalarType,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+  Literal(u32),
+  Value(Value),
+}
+
+impl std::fmt::Display for Value {
+  /// Formats the value the same way it is written in source
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.pointer {
+      Pointer::Static(location) => write!(f, "@{}", location.pointer)?,
+      Pointer::Dynamic(location, 0) => write!(f, "*{}", location.pointer)?,
+      Pointer::Dynamic(location, offset) => write!(f, "*{}+{}", location.pointer, offset)?,
+    }
+    match self.scalar_type {
+      WizardScalarType::Integer(1) => Ok(()),
+      WizardScalarType::Integer(digits) => write!(f, ":{}", digits),
+      WizardScalarType::Bool => write!(f, ":b"),
+      WizardScalarType::Char => write!(f, ":c"),
+    }
+  }
 }
 
-struct Value {
-  DynamicPointer: Pointer,
-  StaticType: WizardScalarType,
+impl std::fmt::Display for Operand {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Operand::Literal(number) => write!(f, "{}", number),
+      Operand::Value(value) => write!(f, "{}", value),
+    }
+  }
 }
 
-enum Function {
-  Print(Value)
+/// Jump targets are the index of the line holding the label.
+/// In bytecode, they are the position of the instruction to jump to instead.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Function {
+  Print(Operand),
+  Set(Value, Operand),
+  Add(Value, Operand),
+  Sub(Value, Operand),
+  Mul(Value, Operand),
+  Jump(usize),
+  JumpIf(Operand, usize),
+  Find(Value, RecordKind, Operand),
+  TileAt(Value, Operand, Operand),
+  MyPos(Value, Value),
+  /// Destination, then x1, y1, x2, y2
+  Distance(Value, [Operand; 4]),
+  Random(Value, Operand),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+  Instruction(Function),
+  Label(String),
+  Comment(String),
+  Blank,
+}
+
+/// Holds exactly one `Line` for every line of source text
+#[derive(Debug, Clone, Default)]
+pub struct AST {
+  pub lines: Vec<Line>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+  /// starts at 0
+  pub line: usize,
+  pub message: String,
+}
+
+impl std::fmt::Display for ParseError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "line {}: {}", self.line + 1, self.message)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  Ident(String),
+  Number(u32),
+  At,
+  Star,
+  Plus,
+  Colon,
+  Comment(String),
+}
+
+fn tokenize_line(text: &str) -> Result<Vec<Token>, String> {
+  let mut tokens = vec![];
+  let mut chars = text.char_indices().peekable();
+
+  while let Some((i, c)) = chars.next() {
+    match c {
+      c if c.is_whitespace() => {}
+      '#' => {
+        tokens.push(Token::Comment(text[i + 1..].trim().to_owned()));
+        break;
+      }
+      '@' => tokens.push(Token::At),
+      '*' => tokens.push(Token::Star),
+      '+' => tokens.push(Token::Plus),
+      ':' => tokens.push(Token::Colon),
+      c if c.is_ascii_digit() => {
+        let mut number = c.to_string();
+        while let Some((_, next)) = chars.peek().filter(|(_, n)| n.is_ascii_digit()) {
+          number.push(*next);
+          chars.next();
+        }
+        let number = number
+          .parse()
+          .map_err(|_| format!("{} is too large", number))?;
+        tokens.push(Token::Number(number));
+      }
+      c if c.is_ascii_alphabetic() || c == '_' => {
+        let mut word = c.to_string();
+        while let Some((_, next)) = chars
+          .peek()
+          .filter(|(_, n)| n.is_ascii_alphanumeric() || *n == '_')
+        {
+          word.push(*next);
+          chars.next();
+        }
+        tokens.push(Token::Ident(word));
+      }
+      _ => return Err(format!("unexpected character '{}'", c)),
+    }
+  }
+
+  Ok(tokens)
 }
 
-enum Line {
-  Instruction,
-  Comment
+/// Labels are a name followed by a colon, and nothing else
+fn as_label(tokens: &[Token]) -> Option<&str> {
+  match tokens {
+    [Token::Ident(name), Token::Colon] | [Token::Ident(name), Token::Colon, Token::Comment(_)] => {
+      Some(name.as_str())
+    }
+    _ => None,
+  }
 }
 
-struct AST {
-  lines: Vec<Line>
+/// Walks through the tokens of a single line
+struct LineParser<'a> {
+  tokens: &'a [Token],
+  position: usize,
+  labels: &'a HashMap<String, usize>,
+}
+
+impl<'a> LineParser<'a> {
+  fn next(&mut self) -> Option<&'a Token> {
+    let token = self.tokens.get(self.position);
+    if token.is_some() {
+      self.position += 1;
+    }
+    token
+  }
+
+  fn peek(&self) -> Option<&'a Token> {
+    self.tokens.get(self.position)
+  }
+
+  fn number(&mut self) -> Result<u32, String> {
+    match self.next() {
+      Some(Token::Number(n)) => Ok(*n),
+      _ => Err("expected a number".to_owned()),
+    }
+  }
+
+  fn scalar_type(&mut self) -> Result<WizardScalarType, String> {
+    if self.peek() != Some(&Token::Colon) {
+      return Ok(WizardScalarType::Integer(1));
+    }
+    self.next();
+    match self.next() {
+      Some(Token::Number(digits)) if (1..=MAX_INTEGER_DIGITS).contains(digits) => {
+        Ok(WizardScalarType::Integer(*digits))
+      }
+      Some(Token::Number(digits)) => Err(format!(
+        "integers must be between 1 and {} digits, not {}",
+        MAX_INTEGER_DIGITS, digits
+      )),
+      Some(Token::Ident(t)) if t == "b" => Ok(WizardScalarType::Bool),
+      Some(Token::Ident(t)) if t == "c" => Ok(WizardScalarType::Char),
+      _ => Err("expected a type after ':' (a digit count, 'b' or 'c')".to_owned()),
+    }
+  }
+
+  fn value(&mut self) -> Result<Value, String> {
+    let pointer = match self.next() {
+      Some(Token::At) => Pointer::Static(MemoryLocation {
+        pointer: self.number()? as usize,
+      }),
+      Some(Token::Star) => {
+        let location = MemoryLocation {
+          pointer: self.number()? as usize,
+        };
+        let mut offset = 0;
+        if self.peek() == Some(&Token::Plus) {
+          self.next();
+          offset = self.number()? as usize;
+        }
+        Pointer::Dynamic(location, offset)
+      }
+      _ => return Err("expected a memory address like @120 or *120".to_owned()),
+    };
+    Ok(Value {
+      pointer,
+      scalar_type: self.scalar_type()?,
+    })
+  }
+
+  fn operand(&mut self) -> Result<Operand, String> {
+    match self.peek() {
+      Some(Token::Number(n)) => {
+        self.next();
+        Ok(Operand::Literal(*n))
+      }
+      _ => Ok(Operand::Value(self.value()?)),
+    }
+  }
+
+  fn form(&mut self) -> Result<RecordKind, String> {
+    match self.next() {
+      Some(Token::Ident(name)) => {
+        RecordKind::from_form_name(name).ok_or_else(|| format!("unknown form '{}'", name))
+      }
+      _ => Err("expected a form, like Enemy".to_owned()),
+    }
+  }
+
+  fn label(&mut self) -> Result<usize, String> {
+    match self.next() {
+      Some(Token::Ident(name)) => self
+        .labels
+        .get(name)
+        .copied()
+        .ok_or_else(|| format!("unknown label '{}'", name)),
+      _ => Err("expected a label".to_owned()),
+    }
+  }
+
+  fn instruction(&mut self) -> Result<Function, String> {
+    let name = match self.next() {
+      Some(Token::Ident(name)) => name.as_str(),
+      _ => return Err("expected an instruction".to_owned()),
+    };
+    let function = match name {
+      "print" => Function::Print(self.operand()?),
+      "set" => Function::Set(self.value()?, self.operand()?),
+      "add" => Function::Add(self.value()?, self.operand()?),
+      "sub" => Function::Sub(self.value()?, self.operand()?),
+      "mul" => Function::Mul(self.value()?, self.operand()?),
+      "jump" => Function::Jump(self.label()?),
+      "jumpif" => Function::JumpIf(self.operand()?, self.label()?),
+      "find" => Function::Find(self.value()?, self.form()?, self.operand()?),
+      "tile" => Function::TileAt(self.value()?, self.operand()?, self.operand()?),
+      "mypos" => Function::MyPos(self.value()?, self.value()?),
+      "dist" => Function::Distance(
+        self.value()?,
+        [
+          self.operand()?,
+          self.operand()?,
+          self.operand()?,
+          self.operand()?,
+        ],
+      ),
+      "random" => Function::Random(self.value()?, self.operand()?),
+      _ => return Err(format!("unknown instruction '{}'", name)),
+    };
+
+    match self.next() {
+      None | Some(Token::Comment(_)) => Ok(function),
+      Some(_) => Err(format!("too many arguments for '{}'", name)),
+    }
+  }
+}
+
+/// Turns spell source text into an `AST`
+pub fn parse(source: &str) -> Result<AST, ParseError> {
+  let tokenized = source
+    .lines()
+    .enumerate()
+    .map(|(line, text)| tokenize_line(text).map_err(|message| ParseError { line, message }))
+    .collect::<Result<Vec<Vec<Token>>, ParseError>>()?;
+
+  // Labels have to be found first, since jumps can go forwards
+  let mut labels = HashMap::default();
+  for (line, tokens) in tokenized.iter().enumerate() {
+    if let Some(name) = as_label(tokens) {
+      if labels.insert(name.to_owned(), line).is_some() {
+        return Err(ParseError {
+          line,
+          message: format!("the label '{}' is used twice", name),
+        });
+      }
+    }
+  }
+
+  let lines = tokenized
+    .iter()
+    .enumerate()
+    .map(|(line, tokens)| {
+      if let Some(name) = as_label(tokens) {
+        return Ok(Line::Label(name.to_owned()));
+      }
+      match tokens.as_slice() {
+        [] => Ok(Line::Blank),
+        [Token::Comment(text)] => Ok(Line::Comment(text.to_owned())),
+        _ => LineParser {
+          tokens,
+          position: 0,
+          labels: &labels,
+        }
+        .instruction()
+        .map(Line::Instruction)
+        .map_err(|message| ParseError { line, message }),
+      }
+    })
+    .collect::<Result<Vec<Line>, ParseError>>()?;
+
+  Ok(AST { lines })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn tokenizes_values_and_comments() {
+    assert_eq!(
+      tokenize_line("add *120+4:3 7 # hit it"),
+      Ok(vec![
+        Token::Ident("add".to_owned()),
+        Token::Star,
+        Token::Number(120),
+        Token::Plus,
+        Token::Number(4),
+        Token::Colon,
+        Token::Number(3),
+        Token::Number(7),
+        Token::Comment("hit it".to_owned()),
+      ])
+    );
+    assert!(tokenize_line("set @1 $").is_err());
+  }
+
+  #[test]
+  fn labels_resolve_to_their_line() {
+    let ast = parse("jump end\nprint 1\nend:").unwrap();
+    assert_eq!(ast.lines[0], Line::Instruction(Function::Jump(2)));
+    assert_eq!(ast.lines[2], Line::Label("end".to_owned()));
+  }
+
+  #[test]
+  fn parse_errors_have_the_line() {
+    let error = parse("print 1\n\nset @5 nope").unwrap_err();
+    assert_eq!(error.line, 2);
+    assert_eq!(error.to_string(), "line 3: expected a memory address like @120 or *120");
+
+    assert_eq!(parse("a:\na:").unwrap_err().line, 1);
+    assert_eq!(parse("jump nowhere").unwrap_err().line, 0);
+  }
 }
@@ -1,5 +1,27 @@
 pub const MEMORY_SIZE: usize = 1_000_000;
 pub const LOCAL_MEMORY: usize = 1_000;
+/// How many digits it takes to store any address in memory
+pub const ADDRESS_WIDTH: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryError {
+    /// The address is past the end of memory
+    OutOfBounds(usize),
+    /// Memory cells can only hold a single digit
+    InvalidDigit(u8),
+    /// The address is in a part of memory that can't be changed
+    Protected(usize),
+}
+
+impl std::fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MemoryError::OutOfBounds(address) => write!(f, "address {} does not exist", address),
+            MemoryError::InvalidDigit(value) => write!(f, "{} is not a single digit", value),
+            MemoryError::Protected(address) => write!(f, "address {} is protected", address),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MemoryCellType {
@@ -14,67 +36,304 @@ pub struct MemoryCell {
 }
 
 impl MemoryCell {
+    /// `value` has to be a single digit, or this panics.
+    /// Digits that come from outside the game, like spells or memory edits, go through `try_new`.
     pub fn new(cell_type: MemoryCellType, value: u8) -> Self {
+        match Self::try_new(cell_type, value) {
+            Ok(cell) => cell,
+            Err(e) => panic!("invalid memory cell: {}", e),
+        }
+    }
+
+    pub fn try_new(cell_type: MemoryCellType, value: u8) -> Result<Self, MemoryError> {
         if value > 9 {
-            panic!("invalid value {} written to memory", value);
+            return Err(MemoryError::InvalidDigit(value));
         }
-        Self { cell_type, value }
+        Ok(Self { cell_type, value })
     }
 }
 
+/// Reads cells as the digits of a single number, most significant first
+pub fn cells_to_number(cells: &[MemoryCell]) -> u32 {
+    cells
+        .iter()
+        .fold(0, |number, cell| number * 10 + cell.value as u32)
+}
+
 impl Default for MemoryCell {
     fn default() -> Self {
         MemoryCell::new(MemoryCellType::Blank, 0)
     }
 }
 
+#[derive(Clone)]
 pub struct MemoryBlob {
     // Even though this has a fixed size, I usee a vector instead of an array to allocate it on the heap
     memory: Vec<MemoryCell>,
+    /// The cells overwritten since `checkpoint`, with their old values
+    journal: Option<Vec<(usize, MemoryCell)>>,
 }
 
 impl MemoryBlob {
     pub fn new() -> Self {
         Self {
             memory: vec![MemoryCell::new(MemoryCellType::Blank, 0); MEMORY_SIZE],
+            journal: None,
         }
     }
+    /// Remembers every write from now on, so that `rollback` can undo them
+    pub fn checkpoint(&mut self) {
+        self.journal = Some(vec![]);
+    }
+    /// Undoes every write since the last `checkpoint`
+    pub fn rollback(&mut self) {
+        if let Some(journal) = self.journal.take() {
+            for (address, cell) in journal.into_iter().rev() {
+                self.memory[address] = cell;
+            }
+        }
+    }
+    /// Writes the cells starting at `start`. If any of them are invalid, nothing is written.
     pub fn write_mem<'a, I: Iterator<Item = &'a MemoryCell>>(
         &mut self,
         values: I,
         start: usize,
-    ) -> Result<(), ()> {
-        if start > MEMORY_SIZE - 1 {
-            Err(())?;
+    ) -> Result<(), MemoryError> {
+        let values: Vec<MemoryCell> = values.copied().collect();
+        self.check_range(start, values.len())?;
+        if let Some(bad) = values.iter().find(|val| val.value > 9) {
+            return Err(MemoryError::InvalidDigit(bad.value));
         }
-        let mut index = start;
-        for val in values {
-            if index > MEMORY_SIZE {
-                Err(())?;
-            }
-            self.memory[index] = *val;
-            index += 1;
+        if let Some(journal) = self.journal.as_mut() {
+            let old = self.memory[start..start + values.len()].iter().copied();
+            journal.extend((start..).zip(old));
         }
-
+        self.memory[start..start + values.len()].copy_from_slice(&values);
         Ok(())
     }
     pub fn reset_player_memory(&mut self) {
         // clear player's working memory
         self.write_mem(
-            std::iter::repeat(&MemoryCell::new(MemoryCellType::Blank, 0)).take(LOCAL_MEMORY - 1),
+            std::iter::repeat_n(&MemoryCell::new(MemoryCellType::Blank, 0), LOCAL_MEMORY),
             0,
         )
         .unwrap();
     }
-    pub fn get_many(&self, start: usize, count: usize) -> &[MemoryCell] {
-      &self.memory[start..start + count]
+    pub fn get_many(&self, start: usize, count: usize) -> Result<&[MemoryCell], MemoryError> {
+        self.check_range(start, count)?;
+        Ok(&self.memory[start..start + count])
     }
     pub fn get_one(&self, address: MemoryLocation) -> Option<&MemoryCell> {
       self.memory.get(address.pointer)
     }
+    /// Makes sure that all of `start..start + count` is in memory
+    fn check_range(&self, start: usize, count: usize) -> Result<(), MemoryError> {
+        match start.checked_add(count) {
+            Some(end) if end <= MEMORY_SIZE => Ok(()),
+            _ => Err(MemoryError::OutOfBounds(start.max(MEMORY_SIZE))),
+        }
+    }
 }
 
-#[derive(Default)]
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
 pub struct MemoryLocation {
   pub pointer: usize,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Following an address stored in memory, with a `*` pointer
+    Execute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const ALL: Permissions = Permissions::new(true, true, true);
+    pub const READ_WRITE: Permissions = Permissions::new(true, true, false);
+    pub const READ_ONLY: Permissions = Permissions::new(true, false, false);
+    pub const NONE: Permissions = Permissions::new(false, false, false);
+
+    pub const fn new(read: bool, write: bool, execute: bool) -> Self {
+        Self {
+            read,
+            write,
+            execute,
+        }
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        }
+    }
+}
+
+impl std::fmt::Display for Permissions {
+    /// Formats like unix file permissions, i.e. `rw-`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let flag = |allowed: bool, c: char| if allowed { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(self.read, 'r'),
+            flag(self.write, 'w'),
+            flag(self.execute, 'x')
+        )
+    }
+}
+
+/// A named part of memory, from `start` up to (but not including) `end`
+#[derive(Debug, Clone)]
+pub struct MemoryRegion {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+    pub permissions: Permissions,
+    /// The form of the records stored in the region, if it holds game objects
+    pub form: Option<String>,
+}
+
+/// Splits memory into regions, and controls what spells can do in each of them.
+/// Addresses that aren't in any region can't be used at all.
+pub struct MemoryMap {
+    regions: Vec<MemoryRegion>,
+}
+
+impl MemoryMap {
+    pub fn region_at(&self, address: usize) -> Option<&MemoryRegion> {
+        self.regions
+            .iter()
+            .find(|r| r.start <= address && address < r.end)
+    }
+
+    pub fn region_mut(&mut self, name: &str) -> Option<&mut MemoryRegion> {
+        self.regions.iter_mut().find(|r| r.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.regions.iter()
+    }
+
+    /// Makes sure that every cell in `start..start + count` can be accessed
+    pub fn check(&self, start: usize, count: usize, access: Access) -> Result<(), MemoryError> {
+        for address in start..start.saturating_add(count) {
+            if address >= MEMORY_SIZE {
+                return Err(MemoryError::OutOfBounds(address));
+            }
+            match self.region_at(address) {
+                Some(region) if region.permissions.allows(access) => {}
+                _ => return Err(MemoryError::Protected(address)),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        use super::wizard_serialize::{CONSTANT_REGION, ENEMY_REGION, PLAYER_REGION, TILE_REGION};
+
+        let regions = [
+            ("scratch", 0, LOCAL_MEMORY, Permissions::ALL, None),
+            ("players", PLAYER_REGION, ENEMY_REGION, Permissions::READ_WRITE, Some("Player")),
+            ("enemies", ENEMY_REGION, TILE_REGION, Permissions::READ_WRITE, Some("Enemy")),
+            ("tiles", TILE_REGION, CONSTANT_REGION, Permissions::READ_WRITE, Some("Tile")),
+            ("constants", CONSTANT_REGION, MEMORY_SIZE, Permissions::READ_ONLY, None),
+        ];
+        Self {
+            regions: regions
+                .iter()
+                .map(|(name, start, end, permissions, form)| MemoryRegion {
+                    name: name.to_string(),
+                    start: *start,
+                    end: *end,
+                    permissions: *permissions,
+                    form: form.map(str::to_owned),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_at_the_end_of_memory() {
+        let memory = MemoryBlob::new();
+        assert_eq!(memory.get_many(MEMORY_SIZE - 1, 1).map(|c| c.len()), Ok(1));
+        assert_eq!(
+            memory.get_many(MEMORY_SIZE - 1, 2).map(|c| c.len()),
+            Err(MemoryError::OutOfBounds(MEMORY_SIZE))
+        );
+        assert_eq!(
+            memory.get_many(usize::MAX, 2).map(|c| c.len()),
+            Err(MemoryError::OutOfBounds(usize::MAX))
+        );
+    }
+
+    #[test]
+    fn writes_at_the_end_of_memory() {
+        let mut memory = MemoryBlob::new();
+        let cell = MemoryCell::new(MemoryCellType::Field, 7);
+        assert_eq!(memory.write_mem([cell].iter(), MEMORY_SIZE - 1), Ok(()));
+        assert_eq!(
+            memory.write_mem([cell, cell].iter(), MEMORY_SIZE - 1),
+            Err(MemoryError::OutOfBounds(MEMORY_SIZE))
+        );
+    }
+
+    #[test]
+    fn local_memory_ends_at_its_region() {
+        let map = MemoryMap::default();
+        assert_eq!(map.check(LOCAL_MEMORY - 1, 1, Access::Execute), Ok(()));
+        assert_eq!(
+            map.check(LOCAL_MEMORY - 1, 2, Access::Execute),
+            Err(MemoryError::Protected(LOCAL_MEMORY))
+        );
+    }
+
+    #[test]
+    fn invalid_digits() {
+        assert_eq!(
+            MemoryCell::try_new(MemoryCellType::Blank, 10).map(|c| c.value),
+            Err(MemoryError::InvalidDigit(10))
+        );
+
+        let mut memory = MemoryBlob::new();
+        let good = MemoryCell::new(MemoryCellType::Field, 3);
+        let bad = MemoryCell {
+            cell_type: MemoryCellType::Field,
+            value: 12,
+        };
+        assert_eq!(
+            memory.write_mem([good, bad].iter(), 0),
+            Err(MemoryError::InvalidDigit(12))
+        );
+        // nothing is written when any cell is invalid
+        assert_eq!(memory.get_many(0, 1).unwrap()[0].value, 0);
+    }
+
+    #[test]
+    fn rollback_undoes_writes() {
+        let mut memory = MemoryBlob::new();
+        let cell = |value| MemoryCell::new(MemoryCellType::Field, value);
+        memory.write_mem([cell(1), cell(2)].iter(), 10).unwrap();
+        memory.checkpoint();
+        memory.write_mem([cell(3)].iter(), 10).unwrap();
+        memory.write_mem([cell(4), cell(5)].iter(), 10).unwrap();
+        memory.rollback();
+        assert_eq!(cells_to_number(memory.get_many(10, 2).unwrap()), 12);
+    }
+}
@@ -0,0 +1,93 @@
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+
+use super::wizard_memory::{MemoryMap, Permissions};
+
+/// The forms that are unlocked at the start of the campaign
+const STARTING_FORMS: &[&str] = &["Player"];
+
+/// Which `WizardForm` signatures the player has unlocked.
+/// Memory holding a locked form can't be seen or changed by spells.
+pub struct Progression {
+  unlocked: HashSet<String>,
+}
+
+impl Progression {
+  pub fn is_unlocked(&self, form: &str) -> bool {
+    self.unlocked.contains(form)
+  }
+
+  /// Returns false if the form was already unlocked
+  pub fn unlock(&mut self, form: &str) -> bool {
+    self.unlocked.insert(form.to_owned())
+  }
+
+  /// Forms needed by a spell that haven't been unlocked yet
+  pub fn missing<'a>(&self, forms: &'a [String]) -> Vec<&'a str> {
+    forms
+      .iter()
+      .filter(|f| !self.is_unlocked(f))
+      .map(|f| f.as_str())
+      .collect()
+  }
+}
+
+impl Default for Progression {
+  fn default() -> Self {
+    Self {
+      unlocked: STARTING_FORMS.iter().map(|f| f.to_string()).collect(),
+    }
+  }
+}
+
+/// The permissions enemy spells run with.
+/// Enemies don't unlock forms as the player does, so every form's memory is open to them.
+#[derive(Default)]
+pub struct EnemyMemoryMap(pub MemoryMap);
+
+/// Locks the memory regions of forms that haven't been unlocked
+pub fn apply_progression(progression: Res<Progression>, mut map: ResMut<MemoryMap>) {
+  if !progression.is_changed() {
+    return;
+  }
+  let permissions: Vec<(String, Permissions)> = map
+    .iter()
+    .filter_map(|region| {
+      let form = region.form.as_ref()?;
+      let permissions = if progression.is_unlocked(form) {
+        Permissions::READ_WRITE
+      } else {
+        Permissions::NONE
+      };
+      Some((region.name.clone(), permissions))
+    })
+    .collect();
+  for (name, permissions) in permissions {
+    if let Some(region) = map.region_mut(&name) {
+      region.permissions = permissions;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::spells::wizard_memory::Access;
+  use crate::spells::wizard_serialize::ENEMY_REGION;
+
+  #[test]
+  fn locked_forms_stay_open_to_enemies() {
+    let mut world = World::new();
+    world.insert_resource(Progression::default());
+    world.insert_resource(MemoryMap::default());
+    world.insert_resource(EnemyMemoryMap::default());
+    SystemStage::single_threaded()
+      .with_system(apply_progression)
+      .run(&mut world);
+
+    let player_map = world.get_resource::<MemoryMap>().unwrap();
+    assert!(player_map.check(ENEMY_REGION, 1, Access::Read).is_err());
+    let enemy_map = world.get_resource::<EnemyMemoryMap>().unwrap();
+    assert!(enemy_map.0.check(ENEMY_REGION, 1, Access::Write).is_ok());
+  }
+}
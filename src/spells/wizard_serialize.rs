@@ -0,0 +1,602 @@
+use bevy::prelude::{info, Entity};
+use bevy_ecs_tilemap::TilePos;
+
+use super::wizard_memory::{
+  cells_to_number, MemoryBlob, MemoryCell, MemoryCellType, ADDRESS_WIDTH, LOCAL_MEMORY, MEMORY_SIZE,
+};
+use super::wizard_types::{FormRegistry, WizardFieldType, WizardForm};
+
+/// Where each kind of record starts in memory.
+/// Players come right after the player's scratch memory.
+pub const PLAYER_REGION: usize = LOCAL_MEMORY;
+pub const ENEMY_REGION: usize = 2_000;
+pub const TILE_REGION: usize = 10_000;
+/// Read-only values that describe the rest of memory, each `ADDRESS_WIDTH` digits long.
+/// In order: the number of players, enemies and tiles, then the size of each of their records.
+pub const CONSTANT_REGION: usize = 900_000;
+
+/// Temperatures are stored in kelvin, so that they are never negative
+const KELVIN_OFFSET: f32 = 273.;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordKind {
+  Player,
+  Enemy,
+  Tile,
+}
+
+impl RecordKind {
+  pub fn from_form_name(name: &str) -> Option<Self> {
+    match name {
+      "Player" => Some(RecordKind::Player),
+      "Enemy" => Some(RecordKind::Enemy),
+      "Tile" => Some(RecordKind::Tile),
+      _ => None,
+    }
+  }
+
+  /// The name of the `WizardForm` that describes the record
+  pub fn form_name(&self) -> &'static str {
+    match self {
+      RecordKind::Player => "Player",
+      RecordKind::Enemy => "Enemy",
+      RecordKind::Tile => "Tile",
+    }
+  }
+}
+
+/// The parts of a player or enemy that are visible in memory
+#[derive(Debug, Clone)]
+pub struct UnitRecord {
+  pub entity: Entity,
+  pub pos: TilePos,
+  pub health: f32,
+  pub max_health: f32,
+  /// Only the wizard and enemy spellcasters have magika. Enemies can't see theirs in memory.
+  pub magika: Option<f32>,
+  /// Only enemies have a speed
+  pub speed: u32,
+}
+
+/// The parts of a data layer tile that are visible in memory
+#[derive(Debug, Clone)]
+pub struct TileRecord {
+  pub entity: Entity,
+  pub pos: TilePos,
+  pub temp: f32,
+  pub wall_health: Option<f32>,
+  /// Used to check if units can be moved onto the tile
+  pub walkable: bool,
+}
+
+/// A copy of the game state, taken right before it is written to memory
+#[derive(Debug, Clone, Default)]
+pub struct WorldSnapshot {
+  pub players: Vec<UnitRecord>,
+  pub enemies: Vec<UnitRecord>,
+  pub tiles: Vec<TileRecord>,
+}
+
+impl WorldSnapshot {
+  /// Sorts the records so that the memory layout is predictable:
+  /// the wizard is always the first player, and tiles are stored row by row.
+  pub fn sort(&mut self) {
+    self
+      .players
+      .sort_by_key(|p| (p.magika.is_none(), p.entity.id()));
+    self.enemies.sort_by_key(|e| e.entity.id());
+    self.tiles.sort_by_key(|t| (t.pos.1, t.pos.0));
+  }
+
+  pub fn tile_at(&self, pos: &TilePos) -> Option<&TileRecord> {
+    self.tiles.iter().find(|t| t.pos == *pos)
+  }
+}
+
+/// Converts game values into something that can be stored in `size` digits
+fn to_memory_number(value: f32, size: usize) -> u32 {
+  let max = 10u32.pow(size as u32) - 1;
+  value.round().clamp(0., max as f32) as u32
+}
+
+pub fn temp_to_memory(temp: f32) -> f32 {
+  temp + KELVIN_OFFSET
+}
+
+pub fn temp_from_memory(kelvin: u32) -> f32 {
+  kelvin as f32 - KELVIN_OFFSET
+}
+
+/// The values of a field in a player or enemy record
+fn unit_field(unit: &UnitRecord, field: &str) -> Vec<f32> {
+  match field {
+    "health" => vec![unit.health],
+    "position" => vec![unit.pos.0 as f32, unit.pos.1 as f32],
+    "magika" => vec![unit.magika.unwrap_or(0.)],
+    "speed" => vec![unit.speed as f32],
+    _ => vec![],
+  }
+}
+
+/// The values of a field in a tile record
+fn tile_field(tile: &TileRecord, field: &str) -> Vec<f32> {
+  match field {
+    "position" => vec![tile.pos.0 as f32, tile.pos.1 as f32],
+    "temperature" => vec![temp_to_memory(tile.temp)],
+    "wall_health" => vec![tile.wall_health.unwrap_or(0.)],
+    _ => vec![],
+  }
+}
+
+/// Where a game entity was written to memory
+#[derive(Debug, Clone)]
+pub struct LayoutRecord {
+  pub entity: Entity,
+  pub kind: RecordKind,
+  pub start: usize,
+  pub size: usize,
+  /// The value of every element of every field when they were written
+  pub original: Vec<u32>,
+}
+
+impl LayoutRecord {
+  /// Where the entity was when it was written to memory, if its form has a position
+  pub fn position(&self, forms: &FormRegistry) -> Option<TilePos> {
+    let form = forms.get(self.kind.form_name())?;
+    let mut index = 0;
+    for (field, field_type) in form.fields.iter() {
+      if field == "position" {
+        return Some(TilePos(*self.original.get(index)?, *self.original.get(index + 1)?));
+      }
+      index += field_type.element_count();
+    }
+    None
+  }
+}
+
+/// The field of a game entity that a memory address belongs to
+pub struct FieldLocation<'a> {
+  pub record: &'a LayoutRecord,
+  pub field: &'a str,
+  pub field_type: &'a WizardFieldType,
+  /// The address of the first cell of the field
+  pub start: usize,
+}
+
+/// Describes where everything was written by the last call to `serialize`
+#[derive(Debug, Clone, Default)]
+pub struct MemoryLayout {
+  pub records: Vec<LayoutRecord>,
+  /// One past the last address that was written
+  pub end: usize,
+}
+
+impl MemoryLayout {
+  pub fn record_at(&self, address: usize) -> Option<&LayoutRecord> {
+    self
+      .records
+      .iter()
+      .find(|r| r.start <= address && address < r.start + r.size)
+  }
+
+  pub fn record_of(&self, entity: Entity) -> Option<&LayoutRecord> {
+    self.records.iter().find(|r| r.entity == entity)
+  }
+
+  pub fn field_at<'a>(
+    &'a self,
+    address: usize,
+    forms: &'a FormRegistry,
+  ) -> Option<FieldLocation<'a>> {
+    let record = self.record_at(address)?;
+    let form = forms.get(record.kind.form_name())?;
+    let (field, field_type, offset) = form.field_at(address - record.start)?;
+    Some(FieldLocation {
+      record,
+      field,
+      field_type,
+      start: record.start + offset,
+    })
+  }
+}
+
+fn number_to_cells(number: u32, size: usize) -> Vec<MemoryCell> {
+  let mut cells = vec![MemoryCell::new(MemoryCellType::Field, 0); size];
+  let mut remaining = number;
+  for cell in cells.iter_mut().rev() {
+    cell.value = (remaining % 10) as u8;
+    remaining /= 10;
+  }
+  cells
+}
+
+/// Writes a single record, with `values` holding the elements of each field of the form
+fn write_record(
+  memory: &mut MemoryBlob,
+  layout: &mut MemoryLayout,
+  form: &WizardForm,
+  entity: Entity,
+  kind: RecordKind,
+  start: usize,
+  values: Vec<Vec<f32>>,
+) {
+  let size = form.size();
+  if start + size > MEMORY_SIZE {
+    return;
+  }
+  let mut cells = Vec::with_capacity(size);
+  let mut original = vec![];
+  for ((_, field_type), mut field_values) in form.fields.iter().zip(values) {
+    let element_size = field_type.element_type().size();
+    field_values.resize(field_type.element_count(), 0.);
+    for value in field_values {
+      let number = to_memory_number(value, element_size);
+      cells.append(&mut number_to_cells(number, element_size));
+      original.push(number);
+    }
+  }
+  if memory.write_mem(cells.iter(), start).is_ok() {
+    layout.end = layout.end.max(start + size);
+    layout.records.push(LayoutRecord {
+      entity,
+      kind,
+      start,
+      size,
+      original,
+    });
+  }
+}
+
+/// Writes the snapshot into memory, replacing whatever game state was there before.
+/// The player's scratch memory is left alone. Records are laid out using the forms in `forms`.
+pub fn serialize(
+  snapshot: &WorldSnapshot,
+  forms: &FormRegistry,
+  memory: &mut MemoryBlob,
+  previous: &MemoryLayout,
+) -> MemoryLayout {
+  if previous.end > LOCAL_MEMORY {
+    memory
+      .write_mem(
+        std::iter::repeat_n(&MemoryCell::default(), previous.end - LOCAL_MEMORY),
+        LOCAL_MEMORY,
+      )
+      .ok();
+  }
+
+  let mut layout = MemoryLayout::default();
+  let regions = [
+    (RecordKind::Player, PLAYER_REGION, ENEMY_REGION),
+    (RecordKind::Enemy, ENEMY_REGION, TILE_REGION),
+    (RecordKind::Tile, TILE_REGION, CONSTANT_REGION),
+  ];
+  for (kind, region, region_end) in regions {
+    let form = match forms.get(kind.form_name()) {
+      Some(form) => form,
+      None => continue,
+    };
+    let size = form.size();
+    // records that don't fit in their region are left out
+    let capacity = (region_end - region) / size.max(1);
+    match kind {
+      RecordKind::Player | RecordKind::Enemy => {
+        let units = if kind == RecordKind::Player {
+          &snapshot.players
+        } else {
+          &snapshot.enemies
+        };
+        for (i, unit) in units.iter().take(capacity).enumerate() {
+          let values = form.fields.iter().map(|(f, _)| unit_field(unit, f)).collect();
+          let start = region + i * size;
+          write_record(memory, &mut layout, form, unit.entity, kind, start, values);
+        }
+      }
+      RecordKind::Tile => {
+        for (i, tile) in snapshot.tiles.iter().take(capacity).enumerate() {
+          let values = form.fields.iter().map(|(f, _)| tile_field(tile, f)).collect();
+          let start = region + i * size;
+          write_record(memory, &mut layout, form, tile.entity, kind, start, values);
+        }
+      }
+    }
+  }
+
+  write_constants(snapshot, forms, memory);
+  layout
+}
+
+fn write_constants(snapshot: &WorldSnapshot, forms: &FormRegistry, memory: &mut MemoryBlob) {
+  let form_size = |kind: RecordKind| forms.get(kind.form_name()).map_or(0, |f| f.size());
+  let constants = [
+    snapshot.players.len(),
+    snapshot.enemies.len(),
+    snapshot.tiles.len(),
+    form_size(RecordKind::Player),
+    form_size(RecordKind::Enemy),
+    form_size(RecordKind::Tile),
+  ];
+  let cells: Vec<MemoryCell> = constants
+    .iter()
+    .flat_map(|c| number_to_cells(to_memory_number(*c as f32, ADDRESS_WIDTH), ADDRESS_WIDTH))
+    .collect();
+  memory.write_mem(cells.iter(), CONSTANT_REGION).ok();
+}
+
+/// A value that was changed in memory since it was serialized
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldChange {
+  pub entity: Entity,
+  pub kind: RecordKind,
+  pub field: String,
+  /// Which value in the field changed. Always 0 for scalar fields.
+  pub element: usize,
+  pub address: usize,
+  pub old: u32,
+  pub new: u32,
+  /// How many digits are different
+  pub changed_cells: usize,
+}
+
+fn changed_digits(old: u32, new: u32, size: usize) -> usize {
+  let (mut old, mut new) = (old, new);
+  let mut changed = 0;
+  for _ in 0..size {
+    if old % 10 != new % 10 {
+      changed += 1;
+    }
+    old /= 10;
+    new /= 10;
+  }
+  changed
+}
+
+/// Finds every value that is different from when it was serialized
+pub fn diff(layout: &MemoryLayout, forms: &FormRegistry, memory: &MemoryBlob) -> Vec<FieldChange> {
+  let mut changes = vec![];
+  for record in layout.records.iter() {
+    let form = match forms.get(record.kind.form_name()) {
+      Some(form) => form,
+      None => continue,
+    };
+    let mut address = record.start;
+    let mut original = record.original.iter();
+    for (field, field_type) in form.fields.iter() {
+      let element_size = field_type.element_type().size();
+      for element in 0..field_type.element_count() {
+        let old = *original.next().unwrap_or(&0);
+        let new = match memory.get_many(address, element_size) {
+          Ok(cells) => cells_to_number(cells),
+          Err(_) => old,
+        };
+        if new != old {
+          changes.push(FieldChange {
+            entity: record.entity,
+            kind: record.kind,
+            field: field.to_owned(),
+            element,
+            address,
+            old,
+            new,
+            changed_cells: changed_digits(old, new, element_size),
+          });
+        }
+        address += element_size;
+      }
+    }
+  }
+  changes
+}
+
+/// Applies changes made to memory to a copy of the snapshot.
+/// Units can only be moved onto empty floor tiles, other moves are ignored.
+pub fn apply_changes(snapshot: &WorldSnapshot, changes: &[FieldChange]) -> WorldSnapshot {
+  let mut result = snapshot.clone();
+  let mut moves: Vec<(Entity, TilePos)> = vec![];
+
+  for change in changes.iter() {
+    let unit = result
+      .players
+      .iter_mut()
+      .chain(result.enemies.iter_mut())
+      .find(|u| u.entity == change.entity);
+    if let Some(unit) = unit {
+      match change.field.as_str() {
+        "health" => unit.health = (change.new as f32).min(unit.max_health),
+        "magika" if unit.magika.is_some() => unit.magika = Some(change.new as f32),
+        "speed" => unit.speed = change.new,
+        "position" => {
+          let index = match moves.iter().position(|(e, _)| *e == change.entity) {
+            Some(index) => index,
+            None => {
+              moves.push((change.entity, unit.pos));
+              moves.len() - 1
+            }
+          };
+          if change.element == 0 {
+            moves[index].1 .0 = change.new;
+          } else {
+            moves[index].1 .1 = change.new;
+          }
+        }
+        _ => {}
+      }
+    } else if let Some(tile) = result.tiles.iter_mut().find(|t| t.entity == change.entity) {
+      match change.field.as_str() {
+        "temperature" => tile.temp = temp_from_memory(change.new),
+        "wall_health" if tile.wall_health.is_some() => {
+          tile.wall_health = Some(change.new as f32)
+        }
+        _ => {}
+      }
+    }
+  }
+
+  for (entity, new_pos) in moves {
+    let is_walkable = result.tile_at(&new_pos).filter(|t| t.walkable).is_some();
+    let is_occupied = result
+      .players
+      .iter()
+      .chain(result.enemies.iter())
+      .any(|u| u.pos == new_pos);
+    if !is_walkable || is_occupied {
+      info!("a spell tried to move a unit to an invalid tile");
+      continue;
+    }
+    if let Some(unit) = result
+      .players
+      .iter_mut()
+      .chain(result.enemies.iter_mut())
+      .find(|u| u.entity == entity)
+    {
+      unit.pos = new_pos;
+    }
+  }
+
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn world() -> WorldSnapshot {
+    let mut world = WorldSnapshot::default();
+    world.players.push(UnitRecord {
+      entity: Entity::from_raw(1),
+      pos: TilePos(0, 0),
+      health: 80.,
+      max_health: 100.,
+      magika: Some(50.),
+      speed: 0,
+    });
+    world.enemies.push(UnitRecord {
+      entity: Entity::from_raw(2),
+      pos: TilePos(1, 0),
+      health: 30.,
+      max_health: 100.,
+      magika: None,
+      speed: 3,
+    });
+    world.tiles.push(TileRecord {
+      entity: Entity::from_raw(3),
+      pos: TilePos(0, 0),
+      temp: -10.,
+      wall_health: None,
+      walkable: true,
+    });
+    world.tiles.push(TileRecord {
+      entity: Entity::from_raw(4),
+      pos: TilePos(1, 0),
+      temp: 20.,
+      wall_health: None,
+      walkable: true,
+    });
+    world.tiles.push(TileRecord {
+      entity: Entity::from_raw(5),
+      pos: TilePos(2, 0),
+      temp: 20.,
+      wall_health: Some(40.),
+      walkable: false,
+    });
+    world.sort();
+    world
+  }
+
+  fn read(memory: &MemoryBlob, address: usize, size: usize) -> u32 {
+    cells_to_number(memory.get_many(address, size).unwrap())
+  }
+
+  fn write(memory: &mut MemoryBlob, address: usize, size: usize, number: u32) {
+    memory
+      .write_mem(number_to_cells(number, size).iter(), address)
+      .unwrap();
+  }
+
+  #[test]
+  fn records_are_written_where_the_forms_say() {
+    let forms = FormRegistry::default();
+    let mut memory = MemoryBlob::new();
+    let layout = serialize(&world(), &forms, &mut memory, &MemoryLayout::default());
+
+    // health, x, y, magika
+    assert_eq!(read(&memory, PLAYER_REGION, 3), 80);
+    assert_eq!(read(&memory, PLAYER_REGION + 3, 6), 0);
+    assert_eq!(read(&memory, PLAYER_REGION + 9, 3), 50);
+    // health, x, y, speed
+    assert_eq!(read(&memory, ENEMY_REGION, 3), 30);
+    assert_eq!(read(&memory, ENEMY_REGION + 3, 6), 1_000);
+    assert_eq!(read(&memory, ENEMY_REGION + 9, 1), 3);
+    // the counts of each record
+    assert_eq!(read(&memory, CONSTANT_REGION, ADDRESS_WIDTH), 1);
+    assert_eq!(read(&memory, CONSTANT_REGION + 2 * ADDRESS_WIDTH, ADDRESS_WIDTH), 3);
+
+    assert_eq!(layout.records.len(), 5);
+    assert!(diff(&layout, &forms, &memory).is_empty());
+  }
+
+  #[test]
+  fn temperatures_are_stored_with_the_kelvin_offset() {
+    let forms = FormRegistry::default();
+    let mut memory = MemoryBlob::new();
+    let world = world();
+    let layout = serialize(&world, &forms, &mut memory, &MemoryLayout::default());
+
+    let temperature = TILE_REGION + 6;
+    assert_eq!(read(&memory, temperature, 4), 263);
+
+    write(&mut memory, temperature, 4, 283);
+    let changes = diff(&layout, &forms, &memory);
+    assert_eq!(changes.len(), 1);
+    assert_eq!((changes[0].old, changes[0].new), (263, 283));
+
+    let after = apply_changes(&world, &changes);
+    assert_eq!(after.tiles[0].temp, 10.);
+  }
+
+  #[test]
+  fn changes_are_read_back() {
+    let forms = FormRegistry::default();
+    let mut memory = MemoryBlob::new();
+    let world = world();
+    let layout = serialize(&world, &forms, &mut memory, &MemoryLayout::default());
+
+    write(&mut memory, PLAYER_REGION, 3, 95);
+    write(&mut memory, ENEMY_REGION + 9, 1, 5);
+    // the wall is the third tile, its health comes after position and temperature
+    write(&mut memory, TILE_REGION + 2 * 13 + 10, 3, 10);
+    let after = apply_changes(&world, &diff(&layout, &forms, &memory));
+
+    assert_eq!(after.players[0].health, 95.);
+    assert_eq!(after.enemies[0].speed, 5);
+    assert_eq!(after.tiles[2].wall_health, Some(10.));
+  }
+
+  #[test]
+  fn health_is_capped_and_bad_moves_are_ignored() {
+    let forms = FormRegistry::default();
+    let mut memory = MemoryBlob::new();
+    let world = world();
+    let layout = serialize(&world, &forms, &mut memory, &MemoryLayout::default());
+
+    write(&mut memory, PLAYER_REGION, 3, 500);
+    // on to the wall
+    write(&mut memory, ENEMY_REGION + 3, 3, 2);
+    let after = apply_changes(&world, &diff(&layout, &forms, &memory));
+
+    assert_eq!(after.players[0].health, 100.);
+    assert_eq!(after.enemies[0].pos, TilePos(1, 0));
+  }
+
+  #[test]
+  fn serializing_again_clears_old_records() {
+    let forms = FormRegistry::default();
+    let mut memory = MemoryBlob::new();
+    let layout = serialize(&world(), &forms, &mut memory, &MemoryLayout::default());
+
+    let mut fewer = world();
+    fewer.enemies.clear();
+    let layout = serialize(&fewer, &forms, &mut memory, &layout);
+    assert_eq!(read(&memory, ENEMY_REGION, 3), 0);
+    assert!(layout.record_of(Entity::from_raw(2)).is_none());
+  }
+}
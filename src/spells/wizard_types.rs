@@ -1,19 +1,137 @@
-use bevy::utils::HashMap;
+/// Number of memory cells (digits) used to store a single character
+pub const CHAR_SIZE: usize = 3;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum WizardScalarType {
+  /// An unsigned integer that is stored in the given number of digits
   Integer(u32),
-  Char(u32),
-  Bool(bool)
+  /// A character, stored as its ascii code
+  Char,
+  /// A single digit, where 0 is false and anything else is true
+  Bool,
 }
 
+impl WizardScalarType {
+  /// How many memory cells the type takes up
+  pub fn size(&self) -> usize {
+    match self {
+      WizardScalarType::Integer(digits) => *digits as usize,
+      WizardScalarType::Char => CHAR_SIZE,
+      WizardScalarType::Bool => 1,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum WizardFieldType {
   Scalar(WizardScalarType),
-  List((u16, WizardScalarType)),
-  Tuple((u8, WizardScalarType))
+  Tuple((u8, WizardScalarType)),
+}
+
+impl WizardFieldType {
+  /// The type of each value in the field
+  pub fn element_type(&self) -> WizardScalarType {
+    match self {
+      WizardFieldType::Scalar(t) => *t,
+      WizardFieldType::Tuple((_, t)) => *t,
+    }
+  }
+
+  /// How many values are in the field
+  pub fn element_count(&self) -> usize {
+    match self {
+      WizardFieldType::Scalar(_) => 1,
+      WizardFieldType::Tuple((count, _)) => *count as usize,
+    }
+  }
+
+  /// How many memory cells the field takes up
+  pub fn size(&self) -> usize {
+    self.element_type().size() * self.element_count()
+  }
+}
+
+/// Describes how a kind of game object is laid out in memory
+#[derive(Debug, Clone)]
+pub struct WizardForm {
+  pub name: String,
+  /// In the order that they are stored in memory
+  pub fields: Vec<(String, WizardFieldType)>,
 }
 
-struct WizardForm {
-  name: String,
-  fields: HashMap<String, WizardFieldType>
+impl WizardForm {
+  pub fn new(name: &str, fields: Vec<(&str, WizardFieldType)>) -> Self {
+    Self {
+      name: name.to_owned(),
+      fields: fields
+        .into_iter()
+        .map(|(field, kind)| (field.to_owned(), kind))
+        .collect(),
+    }
+  }
+
+  /// How many memory cells a single record takes up
+  pub fn size(&self) -> usize {
+    self.fields.iter().map(|(_, kind)| kind.size()).sum()
+  }
+
+  /// Finds the field that contains the cell `offset` cells from the start of the record.
+  /// Also returns how far the field is from the start of the record.
+  pub fn field_at(&self, offset: usize) -> Option<(&str, &WizardFieldType, usize)> {
+    let mut start = 0;
+    for (field, kind) in self.fields.iter() {
+      if offset < start + kind.size() {
+        return Some((field.as_str(), kind, start));
+      }
+      start += kind.size();
+    }
+    None
+  }
+}
+
+/// The forms of every kind of game object that can be written to memory
+pub struct FormRegistry {
+  forms: Vec<WizardForm>,
 }
 
+impl FormRegistry {
+  pub fn get(&self, name: &str) -> Option<&WizardForm> {
+    self.forms.iter().find(|form| form.name == name)
+  }
+}
+
+impl Default for FormRegistry {
+  fn default() -> Self {
+    use WizardFieldType::{Scalar, Tuple};
+    use WizardScalarType::Integer;
+
+    Self {
+      forms: vec![
+        WizardForm::new(
+          "Player",
+          vec![
+            ("health", Scalar(Integer(3))),
+            ("position", Tuple((2, Integer(3)))),
+            ("magika", Scalar(Integer(3))),
+          ],
+        ),
+        WizardForm::new(
+          "Enemy",
+          vec![
+            ("health", Scalar(Integer(3))),
+            ("position", Tuple((2, Integer(3)))),
+            ("speed", Scalar(Integer(1))),
+          ],
+        ),
+        WizardForm::new(
+          "Tile",
+          vec![
+            ("position", Tuple((2, Integer(3)))),
+            ("temperature", Scalar(Integer(4))),
+            ("wall_health", Scalar(Integer(3))),
+          ],
+        ),
+      ],
+    }
+  }
+}
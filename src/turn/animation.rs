@@ -1,5 +1,5 @@
 use bevy::prelude::*;
-use bevy_ecs_tilemap::TileSize;
+use bevy_ecs_tilemap::{TilePos, TileSize};
 
 use super::{
   EndTurn, EntityAction, EntityPendingAction, PendingAttack, StartTurn, TurnStatus, TurnUIState,
@@ -86,6 +86,31 @@ pub fn update_turn_animations(
   }
 }
 
+/// Slows down at the start and end of each step, so units pause on every tile they walk over
+fn ease_step(t: f32) -> f32 {
+  t * t * (3. - 2. * t)
+}
+
+/// Where a unit walking from `start` along `path` is, `ratio` of the way through the turn.
+/// Every step takes the same amount of time.
+fn position_along_path(start: TilePos, path: &[TilePos], ratio: f32) -> Vec2 {
+  let tile_size = TileSize(constants::TILE_SIZE, constants::TILE_SIZE);
+  if path.is_empty() {
+    return utils::world_pos_from_tile_pos(&start, &tile_size);
+  }
+
+  let progress = ratio.clamp(0., 1.) * path.len() as f32;
+  let step = (progress as usize).min(path.len() - 1);
+  let from = if step == 0 { start } else { path[step - 1] };
+  let to = path[step];
+  let t = ease_step((progress - step as f32).min(1.));
+
+  let from_point = utils::world_pos_from_tile_pos(&from, &tile_size);
+  let to_point = utils::world_pos_from_tile_pos(&to, &tile_size);
+  from_point + (to_point - from_point) * t
+}
+
+/// Walks units along the path of their move, or up to the enemy they are attacking
 pub fn run_move_animations(
   turn_q: Query<(
     &TurnDisplayer,
@@ -111,23 +136,20 @@ pub fn run_move_animations(
           }
         }
 
-        match action.action {
-          EntityAction::Move(mov) => Some((transform, mov)),
+        match &action.action {
+          EntityAction::Move(mov) => Some((transform, mov.start, mov.path.as_slice())),
           EntityAction::Attack(PendingAttack {
             starting_position: start,
-            new_standing_position: end,
+            path,
             ..
-          }) => Some((transform, (start, end))),
+          }) => Some((transform, *start, path.as_slice())),
           _ => None,
         }
       })
-      .for_each(|(mut transform, (start, end))| {
-        let tile_size = TileSize(constants::TILE_SIZE, constants::TILE_SIZE);
-        let start_world_point = utils::world_pos_from_tile_pos(&start, &tile_size);
-        let end_world_point = utils::world_pos_from_tile_pos(&end, &tile_size);
-        let dp = end_world_point - start_world_point;
-        transform.translation.x = start_world_point.x + dp.x * animation_ratio;
-        transform.translation.y = start_world_point.y + dp.y * animation_ratio;
+      .for_each(|(mut transform, start, path)| {
+        let point = position_along_path(start, path, animation_ratio);
+        transform.translation.x = point.x;
+        transform.translation.y = point.y;
       })
   }
 }
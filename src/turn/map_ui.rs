@@ -1,18 +1,19 @@
-use std::cmp::Ordering;
+use std::collections::HashSet;
 
 /// Handles components that allow a player to select actions on map,
 /// such as where to move or which enemy to attack.
 use bevy::prelude::*;
-use bevy_ecs_tilemap::{MapQuery, TileParent, TilePos, TileSize};
+use bevy_ecs_tilemap::{TileParent, TilePos, TileSize};
 use bevy_prototype_lyon::prelude::*;
 
 use crate::constants;
-use crate::map::{DataLayer, DrawOnMap, SelectedTile, TileKind, TileSelectedEvent};
+use crate::map::{DrawOnMap, SelectedTile, TileSelectedEvent};
 use crate::map_entities::enemy::Enemy;
 use crate::map_entities::{player::PlayerStatus, MapEntityType, PlayerType};
+use crate::pathfinding::{self, NavGrid};
 use crate::utils;
 
-use super::{EntityAction, EntityPendingAction, PendingAttack, TurnDisplayer};
+use super::{EntityAction, EntityPendingAction, PendingAttack, PendingMove, TurnDisplayer};
 
 #[derive(Component, Debug)]
 pub struct ActionIndicationMarker;
@@ -36,7 +37,7 @@ pub fn draw_action_arrows(
     .iter()
     .filter_map(
       |(center, action)| match (action.is_ready, action.action.to_owned()) {
-        (true, EntityAction::Move((a, b))) => Some((true, a, b)),
+        (true, EntityAction::Move(mov)) => Some((true, mov.start, mov.end())),
         (
           true,
           EntityAction::Attack(PendingAttack {
@@ -163,7 +164,10 @@ pub struct MapActionChooser {
 }
 
 #[derive(Component, Default)]
-pub struct PlayerMoveSelect;
+pub struct PlayerMoveSelect {
+  /// How the player gets to this marker
+  path: Vec<TilePos>,
+}
 
 #[derive(Component)]
 pub struct PlayerAttackSelect {
@@ -234,6 +238,7 @@ impl PlayerMoveChooserBundle {
     parent: &TileParent,
     player_pos: &TilePos,
     player_type: &MapEntityType,
+    path: Vec<TilePos>,
   ) -> Self {
     Self {
       macb: MapActionChooserBundle::new(
@@ -243,7 +248,7 @@ impl PlayerMoveChooserBundle {
         player_type,
         constants::MOVE_SELECTOR_COLOR,
       ),
-      kind: PlayerMoveSelect,
+      kind: PlayerMoveSelect { path },
     }
   }
 }
@@ -276,10 +281,11 @@ impl PlayerAttackChooserBundle {
 /// If a player has been clicked during the PlayerChoose phase of a turn,
 /// or "Move" has been selected in the left UI,
 /// this will spawn markers to show possible moves.
+/// Markers go on every tile the player can walk to in `PLAYER_DEFAULT_MOVE_SPEED` steps.
 pub fn spawn_action_choosers(
   mut commands: Commands,
   mut select_events: EventReader<TileSelectedEvent>,
-  tile_q: Query<(&TilePos, &TileParent, &DataLayer)>,
+  grid: Res<NavGrid>,
   mut player_q: Query<
     (
       &TilePos,
@@ -291,7 +297,6 @@ pub fn spawn_action_choosers(
   >,
   enemy_q: Query<(Entity, &TilePos, &TileParent), (With<Enemy>, Without<PlayerStatus>)>,
   map_marker_q: Query<(Entity, &MapActionChooser)>,
-  mut map_query: MapQuery,
 ) {
   // there really shouldn't be more than one tile click event per frame
   let click_event = select_events.iter().next();
@@ -305,7 +310,7 @@ pub fn spawn_action_choosers(
     {
       // The pending action is not yet ready, because we don't know which square we want to move to
       player_pending_action.is_ready = false;
-      player_pending_action.action = EntityAction::Move((TilePos::default(), TilePos::default()));
+      player_pending_action.action = EntityAction::Move(PendingMove::default());
       build_markers = Some((
         player_pos.to_owned(),
         player_tile_parent.to_owned(),
@@ -344,87 +349,62 @@ pub fn spawn_action_choosers(
 
   // And finally spawn new markers
   if let Some((origin, parent, player_type)) = build_markers {
-    let layer = parent.layer_id;
-    let map = parent.map_id;
-
-    let max_move_distance: i32 = constants::PLAYER_DEFAULT_MOVE_SPEED as i32;
-
-    /*
-    Finding possible move tiles:
-    1. Make a list of rows that are `max_move_distance` or less from the player
-    2. Make a list of columns on those rows that are less than `max_move_distance - $row_distance_from_player`
-    3. Turn the row, column pairs into `TilePos`'s
-    4. For each TilePos, check that the tile type is correct, and the there are no entities in the way
-    5. For each TilePos left over, create a MapActionChooserBundle to put a chooser component on that square
-    */
-
-    ((max_move_distance * -1 + 1)..max_move_distance)
-      .map(|i| {
-        let moves_left = max_move_distance - i.abs();
-        let row = origin.1 as i32 + i;
-        ((moves_left * -1 + 1)..(moves_left)).map(move |j| {
-          let column = origin.0 as i32 + j;
-          (column, row)
-        })
-      })
-      .flatten()
-      .filter_map(|(x, y)| {
-        if x < 0 || y < 0 {
-          None
-        } else {
-          Some(TilePos(x as u32, y as u32))
-        }
-      })
-      .filter_map(move |pos| {
-        // Check if a player is blocking the move
-        if player_q.iter().any(|pd| {
-          match pd.3.action {
-            EntityAction::Move((_, b)) => {
-              if b == pos {
-                return true;
-              }
-            }
-            _ => {}
-          };
-          *pd.0 == pos
-        }) {
-          return None;
-        }
+    let max_steps = constants::PLAYER_DEFAULT_MOVE_SPEED;
+    let map_dim = grid.map_dim();
 
-        if let Ok(tile_entity) = map_query.get_tile_entity(pos, map, layer) {
-          if let Ok((dp, parent, data)) = tile_q.get(tile_entity) {
-            assert!(*dp == pos);
-            if data.kind == TileKind::Floor {
-              return Some((pos, parent.to_owned()));
-            }
-          }
-        }
-        None
-      })
-      .for_each(|(marker_pos, marker_parent)| {
-        if let Some(enemy) = enemy_q.iter().find(|(_, pos, _)| **pos == marker_pos) {
-          commands
-            .spawn()
-            .insert_bundle(PlayerAttackChooserBundle::new(
-              &marker_pos,
-              &marker_parent,
-              &origin.clone(),
-              &player_type,
-              enemy.1,
-              enemy.0,
-            ));
-        } else {
-          commands.spawn().insert_bundle(PlayerMoveChooserBundle::new(
-            &marker_pos,
-            &marker_parent,
-            &origin.clone(),
+    // the other player might already be moving there
+    let claimed = claimed_tiles(player_q.iter().map(|pd| pd.3));
+
+    // Units can only walk on the ground, and can't walk through each other
+    let reachable = pathfinding::reachable_tiles(origin, max_steps, map_dim, |pos| {
+      grid.is_walkable(pos) && !claimed.contains(pos)
+    });
+    for (marker_pos, path) in reachable.iter() {
+      commands.spawn().insert_bundle(PlayerMoveChooserBundle::new(
+        marker_pos,
+        &parent,
+        &origin,
+        &player_type,
+        path.clone(),
+      ));
+    }
+
+    // Enemies can be attacked if the player can get next to them, with a step to spare
+    for (enemy_entity, enemy_pos, enemy_parent) in enemy_q.iter() {
+      let in_reach = pathfinding::neighbours(*enemy_pos, map_dim).any(|pos| {
+        pos == origin
+          || reachable
+            .get(&pos)
+            .is_some_and(|path| (path.len() as u32) < max_steps)
+      });
+      if in_reach {
+        commands
+          .spawn()
+          .insert_bundle(PlayerAttackChooserBundle::new(
+            enemy_pos,
+            enemy_parent,
+            &origin,
             &player_type,
+            enemy_pos,
+            enemy_entity,
           ));
-        }
-      });
+      }
+    }
   }
 }
 
+/// The tiles that players who are ready will end their turn on
+fn claimed_tiles<'a>(actions: impl Iterator<Item = &'a EntityPendingAction>) -> HashSet<TilePos> {
+  actions
+    .filter(|pending| pending.is_ready)
+    .filter_map(|pending| match &pending.action {
+      EntityAction::Move(mov) => Some(mov.end()),
+      EntityAction::Attack(attack) => Some(attack.new_standing_position),
+      _ => None,
+    })
+    .collect()
+}
+
 // If the other player has associated action choosers,
 // we should despawn the old ones, or it will get confusing.
 pub fn despawn_action_choosers(
@@ -448,11 +428,11 @@ pub fn despawn_action_choosers(
 pub fn select_player_move(
   mut commands: Commands,
   select_events: Query<&TilePos, (Added<SelectedTile>, Changed<SelectedTile>)>,
-  select_markers: Query<(&MapActionChooser, &TilePos), With<PlayerMoveSelect>>,
+  select_markers: Query<(&MapActionChooser, &TilePos, &PlayerMoveSelect)>,
   player: Query<(Entity, &MapEntityType), With<PlayerStatus>>,
 ) {
   for clicked_tile in select_events.iter() {
-    for (act, marker_pos) in select_markers.iter() {
+    for (act, marker_pos, select) in select_markers.iter() {
       if clicked_tile == marker_pos {
         let (player_entity, player_type) = player
           .iter()
@@ -464,7 +444,7 @@ pub fn select_player_move(
             .entity(player_entity)
             .remove::<EntityPendingAction>();
           commands.entity(player_entity).insert(EntityPendingAction {
-            action: EntityAction::Move((act.origin, clicked_tile.to_owned())),
+            action: EntityAction::Move(PendingMove::new(act.origin, select.path.clone())),
             is_ready: true,
           });
         }
@@ -473,11 +453,13 @@ pub fn select_player_move(
   }
 }
 
+/// If an attack marker is clicked, the player walks along the shortest path to the enemy and attacks.
 pub fn select_player_attack(
   mut commands: Commands,
   select_events: Query<&TilePos, (Added<SelectedTile>, Changed<SelectedTile>)>,
   select_markers: Query<(&MapActionChooser, &TilePos, &PlayerAttackSelect)>,
-  player: Query<(Entity, &MapEntityType, &TileParent, &TilePos), With<PlayerStatus>>,
+  player: Query<(Entity, &MapEntityType, &TilePos, &EntityPendingAction), With<PlayerStatus>>,
+  grid: Res<NavGrid>,
 ) {
   for tile_pos in select_events.iter() {
     for (action_chooser, marker_pos, pas) in select_markers.iter() {
@@ -490,28 +472,23 @@ pub fn select_player_attack(
             .entity(attacking_player.0)
             .remove::<EntityPendingAction>();
 
-          let st = [
-            utils::adjust_tile_pos(tile_pos, (1, 0)),
-            utils::adjust_tile_pos(tile_pos, (-1, 0)),
-            utils::adjust_tile_pos(tile_pos, (0, 1)),
-            utils::adjust_tile_pos(tile_pos, (0, -1)),
-          ]
-          .iter()
-          .map(|p| (p, utils::euclidean_tile_distance(p, &action_chooser.origin)))
-          .min_by(|a, b| {
-            if a.1 < b.1 {
-              Ordering::Less
-            } else if a.1 > b.1 {
-              Ordering::Greater
-            } else {
-              Ordering::Equal
-            }
-          })
-          .map(|(p, _)| p)
-          .unwrap_or(&utils::adjust_tile_pos(marker_pos, (1, 0)))
-          .to_owned();
-
-          //let map_starting_tile = map_q.get_tile_entity(*tile_pos, map_id, layer_id)
+          // the other player might already be standing next to the enemy
+          let claimed = claimed_tiles(
+            player
+              .iter()
+              .filter(|(e, ..)| *e != attacking_player.0)
+              .map(|(.., pending)| pending),
+          );
+
+          // If there's no way to get next to the enemy, the attack will miss
+          let path = pathfinding::path_next_to(
+            action_chooser.origin,
+            pas.enemy_pos,
+            grid.map_dim(),
+            |p| grid.is_walkable(p) && !claimed.contains(p),
+          )
+          .unwrap_or_default();
+          let st = path.last().copied().unwrap_or(action_chooser.origin);
 
           commands
             .entity(attacking_player.0)
@@ -519,8 +496,9 @@ pub fn select_player_attack(
               action: EntityAction::Attack(PendingAttack {
                 enemy_entity: pas.enemy_entity,
                 enemy_position: pas.enemy_pos,
-                new_standing_position: st.to_owned(),
-                starting_position: attacking_player.3.to_owned(),
+                new_standing_position: st,
+                starting_position: attacking_player.2.to_owned(),
+                path,
               }),
               is_ready: true,
             });
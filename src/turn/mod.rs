@@ -16,6 +16,8 @@ pub struct PendingAttack {
   enemy_position: TilePos,
   new_standing_position: TilePos,
   starting_position: TilePos,
+  /// The tiles walked to get to `new_standing_position`, not including `starting_position`
+  path: Vec<TilePos>,
 }
 
 impl PendingAttack {
@@ -30,14 +32,40 @@ impl PendingAttack {
       enemy_position,
       new_standing_position,
       starting_position,
+      path: vec![],
     }
   }
+
+  /// Sets the path taken to get next to the enemy
+  pub fn with_path(mut self, path: Vec<TilePos>) -> Self {
+    self.path = path;
+    self
+  }
+}
+
+/// A move along a path of tiles
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PendingMove {
+  pub start: TilePos,
+  /// Every tile that is walked on to, in order. Doesn't include `start`.
+  pub path: Vec<TilePos>,
+}
+
+impl PendingMove {
+  pub fn new(start: TilePos, path: Vec<TilePos>) -> Self {
+    Self { start, path }
+  }
+
+  /// Where the unit stops
+  pub fn end(&self) -> TilePos {
+    self.path.last().copied().unwrap_or(self.start)
+  }
 }
 
 /// The possible things an entity can do in a turn
 #[derive(Debug, Clone, PartialEq)]
 pub enum EntityAction {
-  Move((TilePos, TilePos)),
+  Move(PendingMove),
   Attack(PendingAttack),
   Wait,
   Cast(String),
@@ -103,6 +131,13 @@ impl Default for EntityPendingAction {
   }
 }
 
+/// Sent when a turn resolves an `EntityAction::Cast`, so that the spell system can run it
+#[derive(Clone)]
+pub struct CastSpell {
+  pub caster: Entity,
+  pub spell: String,
+}
+
 /// An event that is sent by the UI to indicated a player's action has been chosen
 pub struct PlayerActionChosen {
   pub action_type: EntityPendingAction,
@@ -144,13 +179,26 @@ pub struct CompletedTurn {
   warrior_health: f32,
 }
 
+/// What happened during each turn of the current level that isn't shown on the map,
+/// like spells that clashed
+#[derive(Default)]
+pub struct TurnLog {
+  /// The turn number, and what happened
+  pub entries: Vec<(usize, String)>,
+}
+
+fn clear_turn_log(mut turn_log: ResMut<TurnLog>) {
+  turn_log.entries.clear();
+}
+
 /// Fires at the end of a turn animation
 /// has to update the state/positions of map entities
 pub fn execute_turn(
   player_turn_ended: RemovedComponents<PlayerTurnAnimating>,
   enemy_turn_ended: RemovedComponents<EnemyTurnAnimating>,
-  mut entity_q: Query<(&mut TilePos, &MapEntityType, &mut EntityPendingAction)>,
+  mut entity_q: Query<(Entity, &mut TilePos, &MapEntityType, &mut EntityPendingAction)>,
   mut health: Query<&mut EntityHealth>,
+  mut cast_events: EventWriter<CastSpell>,
 ) {
   let has_player_turn_ended = player_turn_ended.iter().count() > 0;
   let has_enemy_turn_ended = enemy_turn_ended.iter().count() > 0;
@@ -161,16 +209,17 @@ pub fn execute_turn(
 
   entity_q
     .iter_mut()
-    .filter(|(_, kind, _)| {
+    .filter(|(_, _, kind, _)| {
       if let MapEntityType::Player(_) = kind {
         has_player_turn_ended
       } else {
         has_enemy_turn_ended
       }
     })
-    .for_each(|(mut pos, entity_type, mut action)| {
+    .for_each(|(entity, mut pos, entity_type, mut action)| {
       match action.action {
-        EntityAction::Move((_, end)) => {
+        EntityAction::Move(ref mov) => {
+          let end = mov.end();
           pos.0 = end.0;
           pos.1 = end.1;
         }
@@ -196,6 +245,14 @@ pub fn execute_turn(
           pos.0 = new_pos.0;
           pos.1 = new_pos.1;
         }
+        EntityAction::Cast(ref spell) => {
+          // spells from both sides are resolved together once the enemy turn ends,
+          // see `spells::resolve_casts`
+          cast_events.send(CastSpell {
+            caster: entity,
+            spell: spell.to_owned(),
+          });
+        }
         _ => {}
       }
 
@@ -253,9 +310,11 @@ impl Plugin for TurnPlugin {
   fn build(&self, app: &mut App) {
     app
       .insert_resource(TurnUIState::new())
+      .insert_resource(TurnLog::default())
       .add_event::<StartTurn>()
       .add_event::<EndTurn>()
       .add_event::<PlayerActionChosen>()
+      .add_event::<CastSpell>()
       .add_plugin(animation::TurnAnimationPlugin)
       .add_system_set(
         SystemSet::on_update(GameState::Running)
@@ -271,10 +330,12 @@ impl Plugin for TurnPlugin {
           .with_system(map_ui::select_player_attack),
       )
       .add_system_set(
-        SystemSet::on_exit(GameState::Running).with_system(map_ui::despawn_ui_elements),
+        SystemSet::on_exit(GameState::Running)
+          .with_system(map_ui::despawn_ui_elements)
+          .with_system(clear_turn_log),
       )
       // We have to run this system after the update because it is looking for removed components,
       // information about which is only retained for one frame.
-      .add_system_to_stage(CoreStage::PostUpdate, execute_turn);
+      .add_system_to_stage(CoreStage::PostUpdate, execute_turn.label("execute-turn"));
   }
 }